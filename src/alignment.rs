@@ -1,30 +1,46 @@
 use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::BinaryHeap;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, stdout, BufReader, Write};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use clap::{ArgAction, Parser};
 use log::{info, warn};
 use rand::prelude::SliceRandom;
 use rand::{random, Rng, SeedableRng};
 use rust_htslib::bam;
 use rust_htslib::bam::ext::BamRecordExtensions;
-use rust_htslib::bam::{Format, Read};
-
-use crate::cli::check_path_exists;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{FetchDefinition, Format, Read};
+use rust_htslib::htslib;
+use rust_htslib::tpool::ThreadPool;
+
+use crate::cli::{check_path_exists, check_path_exists_or_stdin};
+use crate::region::{merge_regions, parse_bed, parse_depth_bed, DepthMap, Region};
+use crate::sort::{with_sort_order, RecordSorter, SORT_BUFFER_RECORDS};
+use crate::utils::par_map;
 use crate::Runner;
 
+/// The aux tag added to every record with `--tag` - 1 if the record was selected and 0 if not.
+pub const KEEP_AUX_TAG: &[u8] = b"rk";
+
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
 pub struct Alignment {
-    /// Path to the indexed alignment file (SAM/BAM/CRAM) to subsample
-    #[arg(value_parser = check_path_exists, name = "FILE")]
+    /// Path to the alignment file (SAM/BAM/CRAM) to subsample, or - for stdin
+    ///
+    /// Files without an index, and stdin, must be sorted by coordinate and are read in a single
+    /// pass
+    #[arg(value_parser = check_path_exists_or_stdin, name = "FILE")]
     pub aln: PathBuf,
 
     /// Path to the output subsampled alignment file. Defaults to stdout (same format as input)
     ///
-    /// The output is not guaranteed to be sorted. We recommend piping the output to `samtools sort`
+    /// The output is sorted by coordinate, unless `--sort false` is given
     #[arg(short, long, value_name = "FILE")]
     pub output: Option<PathBuf>,
 
@@ -40,17 +56,993 @@ pub struct Alignment {
     #[arg(short, long, value_name = "INT")]
     pub seed: Option<u64>,
 
-    /// When a region has less than the desired coverage, the step size to move along the chromosome
-    /// to find more reads.
-    ///
-    /// The lowest of the step and the minimum end coordinate of the reads in the region will be used.
-    /// This parameter can have a significant impact on the runtime of the subsampling process.
-    #[arg(long, default_value_t = 100, value_name = "INT", value_parser = clap::value_parser!(i64).range(1..))]
+    /// Deprecated - has no effect. Positions with less than the desired coverage are now skipped
+    /// straight to the next read
+    #[arg(long, default_value_t = 100, value_name = "INT", value_parser = clap::value_parser!(i64).range(1..), hide = true)]
     pub step_size: i64,
+
+    /// Report how many reads would be kept for each chromosome, without writing any output
+    ///
+    /// The report is written to stdout
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Write every record, with an `rk:i:1` tag on the selected records and `rk:i:0` on the rest,
+    /// rather than dropping the records that were not selected
+    #[arg(long, conflicts_with = "dry_run")]
+    pub tag: bool,
+
+    /// Write every record, setting the QC-fail flag (0x200) on the records that were not
+    /// selected, rather than dropping them
+    ///
+    /// Can be combined with --tag
+    #[arg(long, conflicts_with = "dry_run")]
+    pub qc_fail: bool,
+
+    /// Only subsample, and output, the reads in this region - e.g., chr1:1000-2000 (1-based and
+    /// inclusive) or chr1
+    ///
+    /// Can be passed multiple times. Reads overlapping the region are included
+    #[arg(long, value_name = "chr:start-end")]
+    pub region: Vec<Region>,
+
+    /// Only subsample, and output, the reads in the regions of this BED file
+    ///
+    /// Can be combined with --region
+    #[arg(long, value_name = "BED", value_parser = check_path_exists)]
+    pub regions_file: Option<PathBuf>,
+
+    /// BED file with a target depth for each region in the fourth column - e.g., a higher depth
+    /// over amplicons or a lower depth over high-copy repeats
+    ///
+    /// --coverage is used outside of these regions. Where regions overlap, the highest depth is
+    /// used
+    #[arg(long, value_name = "BED", value_parser = check_path_exists)]
+    pub depths: Option<PathBuf>,
+
+    /// Keep read pairs together - selecting a read also selects its mate and all of its
+    /// supplementary and secondary records, wherever they are in the genome
+    ///
+    /// Mates that come later on the chromosome count towards the coverage where they align, so
+    /// fewer new reads are selected there
+    #[arg(long)]
+    pub keep_pairs: bool,
+
+    /// Only use alignments with all of these flags set. An integer, hex (e.g. 0x2) or a
+    /// comma-separated list of flag names (e.g. PAIRED,PROPER_PAIR), as in samtools
+    #[arg(long, value_name = "FLAGS", value_parser = parse_flags, default_value = "0")]
+    pub require_flags: u16,
+
+    /// Don't use alignments with any of these flags set - e.g. UNMAP,SECONDARY,SUPPLEMENTARY,DUP
+    #[arg(long, value_name = "FLAGS", value_parser = parse_flags, default_value = "0")]
+    pub exclude_flags: u16,
+
+    /// Only use alignments with at least this mapping quality
+    #[arg(long, value_name = "INT", default_value_t = 0)]
+    pub min_mapq: u8,
+
+    /// Only use alignments that cover at least this many reference bases
+    #[arg(long, value_name = "INT", default_value_t = 0)]
+    pub min_aligned_length: u64,
+
+    /// Write alignments that don't pass the filters to the output unchanged, instead of
+    /// dropping them
+    ///
+    /// Filtered alignments never count towards the coverage
+    #[arg(long)]
+    pub pass_filtered: bool,
+
+    /// Sort the output by coordinate, and set `SO:coordinate` in its header
+    ///
+    /// Selected alignments are held in memory until no alignment selected later can come before
+    /// them, falling back to sorting with temporary files if too many are held. With `--sort
+    /// false`, alignments are written as they are selected and the header says `SO:unsorted`
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = ArgAction::Set)]
+    pub sort: bool,
+
+    /// Write an index alongside the output - `.bai` for BAM (`.csi` if a chromosome is too long
+    /// for BAI) and `.crai` for CRAM
+    ///
+    /// The output must be a sorted BAM or CRAM file
+    #[arg(long, requires = "output", conflicts_with = "dry_run")]
+    pub write_index: bool,
+
+    /// Reference FASTA the alignments are against, used for reading and writing CRAM
+    ///
+    /// Without it, htslib looks the reference up with `REF_PATH`/`REF_CACHE` or the `UR` field of
+    /// the header, which needs network access unless those point to local files
+    #[arg(long, value_name = "FASTA", value_parser = check_path_exists)]
+    pub reference: Option<PathBuf>,
+
+    /// Number of threads to use
+    ///
+    /// Threads are shared by htslib for (de)compressing the input and output, and chromosomes are
+    /// subsampled in parallel. The output is the same for any number of threads
+    #[arg(short = 't', long, default_value_t = 1, value_name = "INT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub threads: u32,
+}
+
+impl Alignment {
+    /// Create an `Alignment` subsampler for the indexed alignment file `aln` with the same
+    /// defaults as the command line. The output is written to stdout unless `output` is set.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rasusa::{Alignment, Runner};
+    ///
+    /// let mut aln = Alignment::new("in.bam".into(), 30);
+    /// aln.output = Some("out.bam".into());
+    /// aln.seed = Some(42);
+    /// aln.run()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn new(aln: PathBuf, coverage: u32) -> Self {
+        Alignment {
+            aln,
+            output: None,
+            output_type: None,
+            coverage,
+            seed: None,
+            step_size: 100,
+            dry_run: false,
+            tag: false,
+            qc_fail: false,
+            region: vec![],
+            regions_file: None,
+            depths: None,
+            keep_pairs: false,
+            require_flags: 0,
+            exclude_flags: 0,
+            min_mapq: 0,
+            min_aligned_length: 0,
+            pass_filtered: false,
+            sort: true,
+            write_index: false,
+            reference: None,
+            threads: 1,
+        }
+    }
+
+    /// Whether `record` passes the `--require-flags`, `--exclude-flags`, `--min-mapq` and
+    /// `--min-aligned-length` filters, and so can be selected.
+    fn passes_filters(&self, record: &bam::Record) -> bool {
+        let flags = record.flags();
+        let aligned_length = (record.reference_end() - record.pos()).max(0) as u64;
+        flags & self.require_flags == self.require_flags
+            && flags & self.exclude_flags == 0
+            && record.mapq() >= self.min_mapq
+            && aligned_length >= self.min_aligned_length
+    }
+
+    /// Whether subsampling is restricted to `--region`s or a `--regions-file`.
+    fn has_regions(&self) -> bool {
+        !self.region.is_empty() || self.regions_file.is_some()
+    }
+
+    /// The parts of the genome to subsample, in header order - either every chromosome, or the
+    /// (merged) `--region`s and `--regions-file` regions.
+    fn intervals(&self, header: &bam::HeaderView) -> Result<Vec<Interval>> {
+        let mut regions = self.region.clone();
+        if let Some(path) = &self.regions_file {
+            let file =
+                File::open(path).context(format!("Failed to open regions file {:?}", path))?;
+            regions.extend(
+                parse_bed(BufReader::new(file))
+                    .context(format!("Failed to parse regions file {:?}", path))?,
+            );
+        }
+        let regions = merge_regions(regions);
+        if let Some(region) = regions
+            .iter()
+            .find(|r| header.tid(r.chrom.as_bytes()).is_none())
+        {
+            return Err(anyhow!(
+                "Region {} is on a chromosome that is not in the alignment header",
+                region
+            ));
+        }
+
+        let mut intervals = vec![];
+        for (tid, chrom) in header.target_names().into_iter().enumerate() {
+            let tid = tid as u32;
+            let chrom_name = String::from_utf8_lossy(chrom);
+            let chrom_len = header.target_len(tid).context(format!(
+                "Failed to get chromosome length for chromosome {}",
+                chrom_name
+            ))? as i64;
+            if !self.has_regions() {
+                intervals.push(Interval {
+                    tid,
+                    start: 0,
+                    end: chrom_len,
+                    name: chrom_name.to_string(),
+                });
+                continue;
+            }
+            for region in regions.iter().filter(|r| r.chrom.as_bytes() == chrom) {
+                let end = region
+                    .end
+                    .map_or(chrom_len, |end| (end as i64).min(chrom_len));
+                if region.start as i64 >= end {
+                    warn!("Region {} is beyond the end of the chromosome", region);
+                    continue;
+                }
+                intervals.push(Interval {
+                    tid,
+                    start: region.start as i64,
+                    end,
+                    name: region.to_string(),
+                });
+            }
+        }
+        Ok(intervals)
+    }
+
+    /// The target depth along each chromosome, indexed by tid - `--coverage`, except in the
+    /// regions of the `--depths` file.
+    fn depth_maps(&self, header: &bam::HeaderView) -> Result<Vec<DepthMap>> {
+        let region_depths = match &self.depths {
+            None => vec![],
+            Some(path) => {
+                let file =
+                    File::open(path).context(format!("Failed to open depths file {:?}", path))?;
+                parse_depth_bed(BufReader::new(file))
+                    .context(format!("Failed to parse depths file {:?}", path))?
+            }
+        };
+        if let Some(rd) = region_depths
+            .iter()
+            .find(|rd| header.tid(rd.region.chrom.as_bytes()).is_none())
+        {
+            return Err(anyhow!(
+                "Region {} in the depths file is on a chromosome that is not in the alignment header",
+                rd.region
+            ));
+        }
+
+        Ok(header
+            .target_names()
+            .into_iter()
+            .map(|chrom| {
+                let chrom_depths = region_depths
+                    .iter()
+                    .filter(|rd| rd.region.chrom.as_bytes() == chrom);
+                DepthMap::new(chrom_depths, self.coverage)
+            })
+            .collect())
+    }
+
+    /// Whether all records are written, annotated with whether they were selected.
+    fn annotates(&self) -> bool {
+        self.tag || self.qc_fail
+    }
+
+    /// Annotates `record` with whether it was selected - the keep tag with `--tag` and the
+    /// QC-fail flag on unselected records with `--qc-fail`.
+    fn annotate(&self, record: &mut bam::Record, keep: bool) -> Result<()> {
+        if self.tag {
+            set_keep_tag(record, keep)?;
+        }
+        if self.qc_fail && !keep {
+            record.set_flags(record.flags() | htslib::BAM_FQCFAIL as u16);
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a record between the sweep and the second pass, without holding on to its name.
+/// Records that are otherwise identical, such as duplicated entries, are told apart by the order
+/// they're read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RecordKey {
+    tid: i32,
+    pos: i64,
+    end: i64,
+    /// A hash of the record's name and flags.
+    name_and_flags: u64,
+    /// The number of records with the same fields read before this one.
+    ordinal: u32,
+}
+
+/// Gives each record read by a fetch its [`RecordKey`]. Records sharing a key, besides the
+/// ordinal, overlap the same regions, so every fetch returns all of them, in the same order - as
+/// long as each fetch counts them with its own `RecordKeys`.
+#[derive(Debug, Default)]
+struct RecordKeys {
+    /// The chromosome and position of the records counted in `seen`.
+    position: (i32, i64),
+    /// The number of records read at `position`, by their key with an ordinal of 0.
+    seen: HashMap<RecordKey, u32>,
+}
+
+impl RecordKeys {
+    /// The key of `record`, which must be read after all the records before it in the fetch.
+    fn key(&mut self, record: &bam::Record) -> RecordKey {
+        let mut hasher = DefaultHasher::new();
+        record.qname().hash(&mut hasher);
+        record.flags().hash(&mut hasher);
+        let mut key = RecordKey {
+            tid: record.tid(),
+            pos: record.pos(),
+            end: record.reference_end(),
+            name_and_flags: hasher.finish(),
+            ordinal: 0,
+        };
+        if self.position != (key.tid, key.pos) {
+            self.position = (key.tid, key.pos);
+            self.seen.clear();
+        }
+        let count = self.seen.entry(key).or_insert(0);
+        key.ordinal = *count;
+        *count += 1;
+        key
+    }
+}
+
+/// The reads selected by the sweep that are written in a second pass.
+#[derive(Debug, Default)]
+struct Selection {
+    /// The selected records when annotating, or passing filtered records through.
+    kept_records: HashSet<RecordKey>,
+    /// The names of the selected templates when keeping pairs.
+    kept_templates: HashSet<Vec<u8>>,
+    /// Records that extend past the end of their region, so they aren't selected again if they
+    /// overlap the next region on the chromosome.
+    spanning_records: HashSet<RecordKey>,
+}
+
+/// The records overlapping the current position of the sweep over an interval, which is read in a
+/// single pass. They are in file order, as fetching the position would return them.
+struct ActiveWindow {
+    records: Vec<KeyedRecord>,
+    /// The next record that passes the filters, which starts after the current position.
+    next: Option<KeyedRecord>,
+    /// Counts the records read from the interval, including those that don't pass the filters.
+    keys: RecordKeys,
+}
+
+/// A record read by the sweep, with the key it's selected by.
+struct KeyedRecord {
+    record: bam::Record,
+    key: RecordKey,
+}
+
+impl ActiveWindow {
+    /// Starts reading the records of the interval started on `reader`, keeping only those that
+    /// pass `filter`.
+    fn new(
+        reader: &mut impl IntervalRecords,
+        filter: impl Fn(&bam::Record) -> bool,
+    ) -> Result<Self> {
+        let mut keys = RecordKeys::default();
+        Ok(ActiveWindow {
+            records: vec![],
+            next: next_passing_record(reader, &mut keys, filter)?,
+            keys,
+        })
+    }
+
+    /// The start of the next record after the current position, if there is one.
+    fn next_start(&self) -> Option<i64> {
+        self.next.as_ref().map(|next| next.record.pos())
+    }
+
+    /// Moves the window to `pos` - dropping the records that end before it and reading those that
+    /// start at or before it.
+    fn advance_to(
+        &mut self,
+        reader: &mut impl IntervalRecords,
+        pos: i64,
+        filter: impl Fn(&bam::Record) -> bool,
+    ) -> Result<()> {
+        self.records
+            .retain(|keyed| keyed.record.reference_end() > pos);
+        while self.next_start().is_some_and(|start| start <= pos) {
+            let keyed = self.next.take().expect("next record is present");
+            if keyed.record.reference_end() > pos {
+                self.records.push(keyed);
+            }
+            self.next = next_passing_record(reader, &mut self.keys, &filter)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads records from `reader` until one passes `filter`. Every record read is counted by `keys`,
+/// so the keys match those of a second pass that reads all of them.
+fn next_passing_record(
+    reader: &mut impl IntervalRecords,
+    keys: &mut RecordKeys,
+    filter: impl Fn(&bam::Record) -> bool,
+) -> Result<Option<KeyedRecord>> {
+    while let Some(record) = reader.next_record()? {
+        let key = keys.key(&record);
+        if filter(&record) {
+            return Ok(Some(KeyedRecord { record, key }));
+        }
+    }
+    Ok(None)
+}
+
+/// A source of the records overlapping each interval, which are read in header order.
+trait IntervalRecords {
+    /// Starts reading the records that overlap `interval`.
+    fn start(&mut self, interval: &Interval) -> Result<()>;
+
+    /// The next record overlapping the interval, in coordinate order.
+    fn next_record(&mut self) -> Result<Option<bam::Record>>;
+}
+
+impl IntervalRecords for bam::IndexedReader {
+    fn start(&mut self, interval: &Interval) -> Result<()> {
+        self.fetch((interval.tid, interval.start, interval.end))
+            .context(format!("Failed to get all records for {}", interval.name))
+    }
+
+    fn next_record(&mut self) -> Result<Option<bam::Record>> {
+        let mut record = bam::Record::new();
+        match self.read(&mut record) {
+            None => Ok(None),
+            Some(result) => {
+                result.context("Failed to parse record")?;
+                Ok(Some(record))
+            }
+        }
+    }
+}
+
+/// The position of a record in a coordinate-sorted file, with unmapped records without a
+/// position last.
+fn sort_position(record: &bam::Record) -> (u32, i64) {
+    let tid = if record.tid() < 0 {
+        u32::MAX
+    } else {
+        record.tid() as u32
+    };
+    (tid, record.pos())
+}
+
+/// A coordinate-sorted input without an index - such as stdin - read in a single pass. The
+/// records of each interval are picked out of the stream as it goes, so the intervals must be
+/// started in order.
+struct SortedStream {
+    reader: bam::Reader,
+    /// The interval being read, as its chromosome, start and end.
+    interval: (u32, i64, i64),
+    /// The next record of the input, which comes after the current interval.
+    pending: Option<bam::Record>,
+    /// Records of the previous interval that extend into the current one, still to be returned.
+    queued: VecDeque<bam::Record>,
+    /// Records of the current interval that extend past its end, so may overlap the next one.
+    carried: Vec<bam::Record>,
+    /// The position of the last record read, to check the input is sorted.
+    last: Option<(u32, i64)>,
+}
+
+impl SortedStream {
+    /// Reads from `reader`, checking its header says it is sorted by coordinate.
+    fn new(reader: bam::Reader) -> Result<Self> {
+        let text = String::from_utf8_lossy(reader.header().as_bytes()).into_owned();
+        let sorted = text
+            .lines()
+            .next()
+            .filter(|line| line.starts_with("@HD"))
+            .is_some_and(|hd| hd.split('\t').any(|field| field == "SO:coordinate"));
+        if !sorted {
+            return Err(anyhow!(
+                "Alignments without an index must be sorted by coordinate, with SO:coordinate in the header. Sort them with samtools sort (and index them to read them more than once)"
+            ));
+        }
+        Ok(SortedStream {
+            reader,
+            interval: (0, 0, 0),
+            pending: None,
+            queued: VecDeque::new(),
+            carried: vec![],
+            last: None,
+        })
+    }
+
+    /// The name of the chromosome `tid`, for error messages.
+    fn chrom_name(&self, tid: u32) -> String {
+        if tid == u32::MAX {
+            return "*".to_string();
+        }
+        String::from_utf8_lossy(self.reader.header().tid2name(tid)).into_owned()
+    }
+
+    /// The next record of the input, checking it doesn't come before the previous one.
+    fn read_next(&mut self) -> Result<Option<bam::Record>> {
+        if let Some(record) = self.pending.take() {
+            return Ok(Some(record));
+        }
+        let mut record = bam::Record::new();
+        match self.reader.read(&mut record) {
+            None => return Ok(None),
+            Some(result) => result.context("Failed to parse record")?,
+        }
+        let position = sort_position(&record);
+        if let Some(last) = self.last.filter(|&last| position < last) {
+            return Err(anyhow!(
+                "Alignments are not sorted by coordinate - {} at {}:{} comes after a record at {}:{}. Sort them with samtools sort",
+                String::from_utf8_lossy(record.qname()),
+                self.chrom_name(position.0),
+                position.1 + 1,
+                self.chrom_name(last.0),
+                last.1 + 1
+            ));
+        }
+        self.last = Some(position);
+        Ok(Some(record))
+    }
+
+    /// Holds on to `record` for the next interval if it extends past the end of this one.
+    fn carry(&mut self, record: &bam::Record) {
+        let (_, _, end) = self.interval;
+        if record.reference_end() > end {
+            self.carried.push(record.clone());
+        }
+    }
+}
+
+impl IntervalRecords for SortedStream {
+    fn start(&mut self, interval: &Interval) -> Result<()> {
+        let (tid, start, end) = (interval.tid, interval.start, interval.end);
+        if (tid, start) < (self.interval.0, self.interval.1) {
+            return Err(anyhow!(
+                "Regions must be in coordinate order when the alignments aren't indexed - {} comes after a region on {}",
+                interval.name,
+                self.chrom_name(self.interval.0)
+            ));
+        }
+        self.queued = self
+            .carried
+            .drain(..)
+            .filter(|record| sort_position(record).0 == tid && record.reference_end() > start)
+            .collect();
+        self.interval = (tid, start, end);
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<bam::Record>> {
+        if let Some(record) = self.queued.pop_front() {
+            self.carry(&record);
+            return Ok(Some(record));
+        }
+        let (tid, start, end) = self.interval;
+        while let Some(record) = self.read_next()? {
+            let (record_tid, pos) = sort_position(&record);
+            if (record_tid, pos) >= (tid, end) {
+                self.pending = Some(record);
+                return Ok(None);
+            }
+            if record_tid < tid || record.reference_end() <= start {
+                continue;
+            }
+            self.carry(&record);
+            return Ok(Some(record));
+        }
+        Ok(None)
+    }
+}
+
+/// The alignments to subsample - an indexed file, or a coordinate-sorted stream.
+enum Input {
+    Indexed(bam::IndexedReader),
+    Stream(Box<SortedStream>),
+}
+
+impl Input {
+    fn header(&self) -> &bam::HeaderView {
+        match self {
+            Input::Indexed(reader) => reader.header(),
+            Input::Stream(stream) => stream.reader.header(),
+        }
+    }
+
+    fn set_thread_pool(&mut self, tpool: &ThreadPool) -> Result<()> {
+        match self {
+            Input::Indexed(reader) => reader.set_thread_pool(tpool),
+            Input::Stream(stream) => stream.reader.set_thread_pool(tpool),
+        }
+        .context("Failed to set reader thread pool")
+    }
+
+    /// Reads the next record of the second pass, which reads the records fetched from an
+    /// indexed file, or the whole of a stream.
+    fn read(&mut self, record: &mut bam::Record) -> Option<rust_htslib::errors::Result<()>> {
+        match self {
+            Input::Indexed(reader) => reader.read(record),
+            Input::Stream(stream) => stream.reader.read(record),
+        }
+    }
+}
+
+impl IntervalRecords for Input {
+    fn start(&mut self, interval: &Interval) -> Result<()> {
+        match self {
+            Input::Indexed(reader) => reader.start(interval),
+            Input::Stream(stream) => stream.start(interval),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<bam::Record>> {
+        match self {
+            Input::Indexed(reader) => reader.next_record(),
+            Input::Stream(stream) => stream.next_record(),
+        }
+    }
+}
+
+/// A chromosome subsampled in parallel with the others.
+struct ChromosomeResult {
+    /// The name of each interval on the chromosome, and the number of reads kept in it.
+    kept: Vec<(String, u64)>,
+    kept_records: HashSet<RecordKey>,
+    /// The temporary file holding the selected records, when they are written in the sweep.
+    chunk: Option<PathBuf>,
+}
+
+/// Where the records selected by the sweep are written - directly, or through a sorter.
+struct RecordSink {
+    writer: Option<bam::Writer>,
+    sorter: Option<RecordSorter>,
+}
+
+impl RecordSink {
+    fn push(&mut self, record: bam::Record) -> Result<()> {
+        match (&mut self.sorter, &mut self.writer) {
+            (Some(sorter), _) => sorter.push(record),
+            (None, Some(writer)) => writer.write(&record).context("Failed to write record"),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Writes the sorted records that start before `pos` on `tid`.
+    fn flush_before(&mut self, tid: u32, pos: i64) -> Result<()> {
+        match (&mut self.sorter, &mut self.writer) {
+            (Some(sorter), Some(writer)) => sorter.flush_before(tid, pos, writer),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes any records still held by the sorter.
+    fn finish(&mut self) -> Result<()> {
+        match (self.sorter.take(), &mut self.writer) {
+            (Some(sorter), Some(writer)) => sorter.finish(writer),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Groups `intervals`, which are in header order, by chromosome.
+fn group_by_chromosome(intervals: &[Interval]) -> Vec<(u32, Vec<&Interval>)> {
+    let mut chromosomes: Vec<(u32, Vec<&Interval>)> = vec![];
+    for interval in intervals {
+        match chromosomes.last_mut() {
+            Some((tid, chrom_intervals)) if *tid == interval.tid => chrom_intervals.push(interval),
+            _ => chromosomes.push((interval.tid, vec![interval])),
+        }
+    }
+    chromosomes
+}
+
+/// A part of a chromosome to subsample - 0-based and half-open.
+#[derive(Debug)]
+struct Interval {
+    tid: u32,
+    start: i64,
+    end: i64,
+    /// The chromosome or region, for reporting.
+    name: String,
+}
+
+/// Sets [`KEEP_AUX_TAG`] on `record`, replacing any existing value - e.g., from a previous run.
+pub(crate) fn set_keep_tag(record: &mut bam::Record, keep: bool) -> Result<()> {
+    if record.aux(KEEP_AUX_TAG).is_ok() {
+        record
+            .remove_aux(KEEP_AUX_TAG)
+            .context("Failed to remove existing keep tag")?;
+    }
+    record
+        .push_aux(KEEP_AUX_TAG, Aux::U8(u8::from(keep)))
+        .context("Failed to add keep tag to record")
 }
 
 impl Runner for Alignment {
     fn run(&mut self) -> Result<()> {
+        let result = self.subsample();
+        // htslib errors from CRAM files are usually because the reference couldn't be found
+        match result {
+            Err(e)
+                if self.uses_cram() && e.chain().any(|c| c.is::<rust_htslib::errors::Error>()) =>
+            {
+                let hint = match &self.reference {
+                    None => String::from(
+                        "CRAM needs the reference the alignments are against - pass it with \
+                         --reference if it can't be found with REF_PATH or the header",
+                    ),
+                    Some(path) => format!(
+                        "Check that {:?} is the reference the alignments are against",
+                        path
+                    ),
+                };
+                Err(e.context(hint))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Alignment {
+    /// Whether the input or output is CRAM.
+    fn uses_cram(&self) -> bool {
+        let input = infer_format_from_path(&self.aln);
+        let output = match (self.output_type, &self.output) {
+            (Some(fmt), _) => Some(fmt),
+            (None, Some(path)) => infer_format_from_path(path),
+            (None, None) => input,
+        };
+        [input, output]
+            .iter()
+            .any(|fmt| matches!(fmt, Some(Format::Cram)))
+    }
+
+    /// Subsamples the `intervals` on one chromosome, returning the name of each interval and the
+    /// number of reads kept in it.
+    fn subsample_chromosome(
+        &self,
+        reader: &mut impl IntervalRecords,
+        intervals: &[&Interval],
+        depth_map: &DepthMap,
+        seed: u64,
+        selection: &mut Selection,
+        sink: &mut RecordSink,
+    ) -> Result<Vec<(String, u64)>> {
+        let mut rng = rand_pcg::Pcg64::seed_from_u64(seed);
+        intervals
+            .iter()
+            .map(|interval| {
+                let nb_kept = self
+                    .subsample_interval(reader, interval, depth_map, &mut rng, selection, sink)?;
+                Ok((interval.name.clone(), nb_kept))
+            })
+            .collect()
+    }
+
+    /// Sweeps along `interval`, selecting reads until each position has the target depth.
+    /// Returns the number of reads kept.
+    fn subsample_interval(
+        &self,
+        reader: &mut impl IntervalRecords,
+        interval: &Interval,
+        depth_map: &DepthMap,
+        rng: &mut rand_pcg::Pcg64,
+        selection: &mut Selection,
+        sink: &mut RecordSink,
+    ) -> Result<u64> {
+        let chrom_name = &interval.name;
+        let tid = interval.tid;
+
+        info!("Subsampling: {}", chrom_name);
+
+        let mut current_reads = HashSet::new();
+        let mut heap = BinaryHeap::new();
+
+        // the interval is read in a single pass, holding the records that overlap the current
+        // position
+        reader.start(interval)?;
+        let passes_filters = |record: &bam::Record| self.passes_filters(record);
+        let mut window =
+            ActiveWindow::new(reader, passes_filters).context("Failed to get first record")?;
+        let Some(first_start) = window.next_start() else {
+            warn!("{} has no records", chrom_name);
+            return Ok(0);
+        };
+
+        let mut next_pos = first_start.max(interval.start);
+        let first_pos = next_pos;
+        let mut regions_below_coverage = false;
+        let mut nb_kept: u64 = 0;
+
+        loop {
+            window
+                .advance_to(reader, next_pos, passes_filters)
+                .context(format!(
+                    "Failed to read records in region {}:{}-{}",
+                    chrom_name,
+                    next_pos,
+                    next_pos + 1
+                ))?;
+            let records = &window.records;
+            let mut order: Vec<usize> = (0..records.len()).collect();
+
+            if next_pos == first_pos {
+                // we just shuffle all the reads in the first position
+                order.shuffle(rng);
+            } else {
+                // need to sort records by their alignment start positions. those with the same start
+                // position should be shuffled so that the order is random
+                random_sort(&mut order, |&i| records[i].record.pos(), &mut *rng);
+            }
+
+            // the mates of already selected reads count towards the coverage
+            if self.keep_pairs {
+                for record in order.iter().map(|&i| &records[i].record) {
+                    let qname = record.qname();
+                    if selection.kept_templates.contains(qname) && !current_reads.contains(qname) {
+                        current_reads.insert(qname.to_owned());
+                        heap.push(Reverse((record.reference_end(), qname.to_owned())));
+                    }
+                }
+            }
+
+            let pos = next_pos;
+            // any record selected from here on overlaps this position, so it can't start before
+            // the records fetched here
+            let window_start = records
+                .iter()
+                .map(|keyed| keyed.record.pos())
+                .min()
+                .unwrap_or(pos);
+            sink.flush_before(tid, window_start)?;
+            let n_reads_needed = depth_map
+                .depth_at(pos)
+                .saturating_sub(current_reads.len() as u32);
+            let mut num_output = 0;
+            let mut record_iter = order.iter().rev().map(|&i| &records[i]);
+
+            while num_output < n_reads_needed {
+                let KeyedRecord { record, key } = match record_iter.next() {
+                    Some(r) => r,
+                    None => break,
+                };
+                let qname = record.qname().to_owned();
+
+                if current_reads.contains(&qname) {
+                    continue;
+                }
+                current_reads.insert(qname.to_owned());
+                heap.push(Reverse((record.reference_end(), qname.to_owned())));
+                // write the record, unless it was already written for the previous region
+                let already_written = selection.spanning_records.contains(key);
+                if record.reference_end() > interval.end {
+                    selection.spanning_records.insert(*key);
+                }
+                if !already_written {
+                    if self.keep_pairs {
+                        selection.kept_templates.insert(qname);
+                    } else if self.annotates() || self.pass_filtered {
+                        selection.kept_records.insert(*key);
+                    } else {
+                        sink.push(record.clone())?;
+                    }
+                }
+                nb_kept += 1;
+                num_output += 1;
+            }
+
+            if num_output < n_reads_needed {
+                // every read here was selected. if none of them are still open, skip ahead to
+                // the next read - otherwise more are looked for where the first of them ends
+                if heap.is_empty() {
+                    next_pos = window.next_start().unwrap_or(interval.end);
+                }
+                regions_below_coverage = true;
+            }
+
+            // if the target depth changes before the next read ends, move to where it changes
+            // so the new target applies from there
+            let next_end = heap.peek().map(|Reverse((end, _))| *end);
+            let depth_change = depth_map
+                .next_boundary(pos)
+                .filter(|&boundary| match next_end {
+                    Some(end) => boundary < end,
+                    None => next_pos == pos || boundary < next_pos,
+                });
+
+            if let Some(boundary) = depth_change {
+                next_pos = boundary;
+            } else {
+                // remove smallest end position from heap and update next_pos allowing for the
+                // fact that there may be multiple reads with the same end position
+                while let Some(Reverse((end, qname))) = heap.pop() {
+                    next_pos = end;
+
+                    if current_reads.contains(&qname) {
+                        current_reads.remove(&qname);
+                    } else {
+                        return Err(anyhow!("A read in the heap was not found in the current reads set. This should not happen, please raise an issue."));
+                    }
+                    if heap.is_empty() {
+                        break;
+                    }
+                    if let Some(Reverse((next_end, _))) = heap.peek() {
+                        if *next_end != end {
+                            break;
+                        }
+                    } else {
+                        break;
+                    }
+                }
+            }
+
+            // no reads are needed, and there are none, until the end of the interval
+            if next_pos >= interval.end || next_pos == pos {
+                break;
+            }
+        }
+        if regions_below_coverage {
+            warn!(
+                "{} has regions with less than the requested coverage",
+                chrom_name
+            );
+        }
+        Ok(nb_kept)
+    }
+
+    /// Whether the alignments are read from stdin.
+    fn reads_stdin(&self) -> bool {
+        self.aln == Path::new("-")
+    }
+
+    /// Opens the indexed input, with the reference if one was given.
+    fn open_reader(&self) -> Result<bam::IndexedReader> {
+        let mut reader =
+            bam::IndexedReader::from_path(&self.aln).context("Failed to read alignment file")?;
+        if let Some(reference) = &self.reference {
+            reader
+                .set_reference(reference)
+                .context(format!("Failed to use reference {:?}", reference))?;
+        }
+        Ok(reader)
+    }
+
+    /// Opens the input from the start without its index, with the reference if one was given.
+    fn open_unindexed(&self) -> Result<bam::Reader> {
+        let mut reader = if self.reads_stdin() {
+            bam::Reader::from_stdin().context("Failed to read alignments from stdin")?
+        } else {
+            bam::Reader::from_path(&self.aln).context("Failed to read alignment file")?
+        };
+        if let Some(reference) = &self.reference {
+            reader
+                .set_reference(reference)
+                .context(format!("Failed to use reference {:?}", reference))?;
+        }
+        Ok(reader)
+    }
+
+    /// Opens the input - by its index if it has one, otherwise as a coordinate-sorted stream.
+    fn open_input(&self) -> Result<Input> {
+        if self.reads_stdin() {
+            info!("Reading alignments from stdin");
+            return Ok(Input::Stream(Box::new(SortedStream::new(
+                self.open_unindexed()?,
+            )?)));
+        }
+        match self.open_reader() {
+            Ok(reader) => Ok(Input::Indexed(reader)),
+            Err(e)
+                if matches!(
+                    e.downcast_ref(),
+                    Some(rust_htslib::errors::Error::BamInvalidIndex { .. })
+                ) =>
+            {
+                info!(
+                    "No index found for {:?} - reading it in a single pass",
+                    self.aln
+                );
+                Ok(Input::Stream(Box::new(SortedStream::new(
+                    self.open_unindexed()?,
+                )?)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn subsample(&mut self) -> Result<()> {
         info!("Subsampling alignment file: {:?}", self.aln);
 
         let mut rng = match self.seed {
@@ -58,12 +1050,33 @@ impl Runner for Alignment {
             None => rand_pcg::Pcg64::seed_from_u64(random()),
         };
 
-        let mut reader =
-            bam::IndexedReader::from_path(&self.aln).context("Failed to read alignment file")?;
-        let header = bam::Header::from_template(reader.header());
+        // htslib's threads are shared by the reader and writer
+        let tpool = match self.threads {
+            1 => None,
+            n => Some(ThreadPool::new(n).context("Failed to create thread pool")?),
+        };
+        // when annotating, keeping pairs or passing filtered records through, the selected records
+        // are written in a second pass over the (coordinate-sorted) input
+        let second_pass = self.annotates() || self.keep_pairs || self.pass_filtered;
+        if second_pass && self.reads_stdin() {
+            return Err(anyhow!(
+                "--tag, --qc-fail, --keep-pairs and --pass-filtered read the input twice, so can't read it from stdin"
+            ));
+        }
+        let mut input = self.open_input()?;
+        if let Some(tpool) = &tpool {
+            input.set_thread_pool(tpool)?;
+        }
+        let sorted = self.sort || second_pass;
+        let header = with_sort_order(
+            input.header(),
+            if sorted { "coordinate" } else { "unsorted" },
+        );
 
         let input_fmt = match infer_format_from_path(&self.aln) {
             Some(fmt) => fmt,
+            // the format of stdin is detected by htslib, and BAM is written by default
+            None if self.reads_stdin() => Format::Bam,
             None => {
                 return Err(anyhow::anyhow!(
                     "Output file format not recognized. Please use .sam, .bam, or .cram extensions"
@@ -86,159 +1099,378 @@ impl Runner for Alignment {
             },
         };
 
+        // htslib only loads the reference for CRAM output when the writer is created (and the
+        // header written), so it is given in the UR field of the header's @SQ lines
+        let header = match (&self.reference, output_fmt) {
+            (Some(reference), Format::Cram) => with_reference_url(&header, reference)?,
+            _ => header,
+        };
+
+        if self.write_index {
+            if matches!(output_fmt, Format::Sam) {
+                return Err(anyhow!("--write-index needs BAM or CRAM output"));
+            }
+            if !sorted {
+                return Err(anyhow!("--write-index needs sorted output"));
+            }
+        }
+        // htslib always gives CRAM a CRAI index, whatever type is asked for
+        let index_type = match output_fmt {
+            Format::Bam => index_type(input.header()),
+            _ => bam::index::Type::Bai,
+        };
+
+        let intervals = self.intervals(input.header())?;
+        let depth_maps = self.depth_maps(input.header())?;
+
         let mut writer = match &self.output {
+            _ if self.dry_run => {
+                info!("Dry run - no output will be written");
+                None
+            }
             Some(path) => {
                 let path = path.as_path();
 
                 let output = bam::Writer::from_path(path, &header, output_fmt)
                     .context("Failed to create output alignment file")?;
                 info!("Writing subsampled alignment to: {:?}", path);
-                output
+                Some(output)
             }
             None => {
                 let output = bam::Writer::from_stdout(&header, output_fmt)
                     .context("Failed to create output alignment file")?;
                 info!("Writing subsampled alignment to stdout");
-                output
+                Some(output)
             }
         };
+        if let (Some(writer), Some(tpool)) = (writer.as_mut(), &tpool) {
+            writer
+                .set_thread_pool(tpool)
+                .context("Failed to set writer thread pool")?;
+        }
 
-        let header = reader.header().clone();
-        let chroms = header.target_names();
-
-        for chrom in chroms {
-            let chrom_name = String::from_utf8_lossy(chrom);
-
-            info!("Subsampling chromosome: {}", chrom_name);
-
-            let tid = header
-                .tid(chrom)
-                .context(format!("Failed to get tid for chromosome {}", chrom_name))?;
-            let chrom_len = header.target_len(tid).context(format!(
-                "Failed to get chromosome length for chromosome {}",
-                chrom_name
-            ))?;
-            let mut n_reads_needed = self.coverage;
-            let mut current_reads = HashSet::new();
-            let mut heap = BinaryHeap::new();
-
-            // get the 0-based position of the first record in the chromosome
-            reader.fetch(tid).context(format!(
-                "Failed to get all records for chromosome {}",
-                chrom_name
-            ))?;
+        let sorter = match writer {
+            Some(_) if self.sort && !second_pass => {
+                Some(RecordSorter::new(&header, SORT_BUFFER_RECORDS))
+            }
+            _ => None,
+        };
+        let mut sink = RecordSink { writer, sorter };
+
+        // each chromosome gets its own random number generator, so the output doesn't depend on
+        // the order chromosomes are subsampled in
+        let chrom_seeds: Vec<u64> = (0..input.header().target_count())
+            .map(|_| rng.gen())
+            .collect();
+        let chromosomes = group_by_chromosome(&intervals);
+        // a stream can only be read in order, so chromosomes are subsampled in parallel only
+        // when the input is indexed
+        let nb_workers = match input {
+            Input::Indexed(_) => (self.threads as usize).min(chromosomes.len()),
+            Input::Stream(_) => 1,
+        };
 
-            let first_record = if let Some(first_record) = reader.records().next() {
-                first_record.context("Failed to get first record")?
-            } else {
-                warn!("Chromosome {} has no records", chrom_name);
-                continue;
+        let mut kept_per_chrom: Vec<(String, u64)> = Vec::with_capacity(intervals.len());
+        let mut selection = Selection::default();
+        if nb_workers > 1 && self.keep_pairs {
+            info!("Subsampling one chromosome at a time, as --keep-pairs counts mates on other chromosomes");
+        }
+        if nb_workers > 1 && !self.keep_pairs {
+            // each chromosome's selected records are written to a temporary file, and the files
+            // are written to the output in chromosome order
+            let chunk_dir = match sink.writer {
+                Some(_) if !second_pass => Some(
+                    tempfile::Builder::new()
+                        .prefix("rasusa")
+                        .tempdir()
+                        .context("Failed to create temporary directory")?,
+                ),
+                _ => None,
             };
-
-            let mut next_pos = first_record.pos();
-            let first_pos = next_pos;
-            let mut regions_below_coverage = false;
-
-            loop {
-                reader
-                    .fetch((tid, next_pos, next_pos + 1))
-                    .context(format!(
-                        "Failed to fetch records in region {}:{}-{}",
-                        chrom_name,
-                        next_pos,
-                        next_pos + 1
-                    ))?;
-                let records = reader.records();
-
-                let mut records: Vec<_> = records.filter_map(Result::ok).collect();
-
-                if next_pos == first_pos {
-                    // we just shuffle all the reads in the first position
-                    records.shuffle(&mut rng);
-                } else {
-                    // need to sort records by their alignment start positions. those with the same start
-                    // position should be shuffled so that the order is random
-                    random_sort(&mut records, |record| record.pos(), &mut rng);
-                }
-
-                let mut num_output = 0;
-                let mut record_iter = records.into_iter().rev();
-
-                while num_output < n_reads_needed {
-                    let record = match record_iter.next() {
-                        Some(r) => r,
-                        None => break,
+            // htslib's thread pools can't be shared between threads, so each chromosome's reader
+            // and writer get a pool with their share of the threads
+            let worker_threads = self.threads / nb_workers as u32;
+            let results = par_map(
+                &chromosomes,
+                nb_workers,
+                |(tid, intervals)| -> Result<ChromosomeResult> {
+                    let worker_tpool = match worker_threads {
+                        0 | 1 => None,
+                        n => Some(ThreadPool::new(n).context("Failed to create thread pool")?),
                     };
-                    let qname = record.qname().to_owned();
-
-                    if current_reads.contains(&qname) {
-                        continue;
+                    let mut reader = self.open_reader()?;
+                    if let Some(tpool) = &worker_tpool {
+                        reader
+                            .set_thread_pool(tpool)
+                            .context("Failed to set reader thread pool")?;
                     }
-                    current_reads.insert(qname.to_owned());
-                    heap.push(Reverse((record.reference_end(), qname.to_owned())));
-                    // write the record
-                    writer.write(&record).context("Failed to write record")?;
-                    num_output += 1;
-                }
-
-                n_reads_needed -= num_output;
-
-                if n_reads_needed > 0 {
-                    // increment next_pos by step_size or the minimum end position of the reads in
-                    // the region, whichever is smaller
-                    let min_end = heap.peek().map(|Reverse((end, _))| *end);
-                    match min_end {
-                        Some(min_end) => {
-                            next_pos += self.step_size.min(min_end - next_pos);
+                    let chunk = chunk_dir
+                        .as_ref()
+                        .map(|dir| dir.path().join(format!("chrom{}.bam", tid)));
+                    let writer = match &chunk {
+                        Some(path) => {
+                            let mut writer = bam::Writer::from_path(path, &header, Format::Bam)
+                                .context("Failed to create temporary alignment file")?;
+                            writer
+                                .set_compression_level(bam::CompressionLevel::Fastest)
+                                .context("Failed to set temporary file compression")?;
+                            if let Some(tpool) = &worker_tpool {
+                                writer
+                                    .set_thread_pool(tpool)
+                                    .context("Failed to set writer thread pool")?;
+                            }
+                            Some(writer)
                         }
-                        None => {
-                            next_pos += self.step_size;
+                        None => None,
+                    };
+                    let sorter = match writer {
+                        Some(_) if self.sort => {
+                            Some(RecordSorter::new(&header, SORT_BUFFER_RECORDS / nb_workers))
                         }
+                        _ => None,
+                    };
+                    let mut sink = RecordSink { writer, sorter };
+                    let mut selection = Selection::default();
+                    let kept = self.subsample_chromosome(
+                        &mut reader,
+                        intervals,
+                        &depth_maps[*tid as usize],
+                        chrom_seeds[*tid as usize],
+                        &mut selection,
+                        &mut sink,
+                    )?;
+                    sink.finish()?;
+                    Ok(ChromosomeResult {
+                        kept,
+                        kept_records: selection.kept_records,
+                        chunk,
+                    })
+                },
+            );
+            for result in results {
+                let ChromosomeResult {
+                    kept,
+                    kept_records,
+                    chunk,
+                } = result?;
+                kept_per_chrom.extend(kept);
+                selection.kept_records.extend(kept_records);
+                if let (Some(chunk), Some(writer)) = (chunk, sink.writer.as_mut()) {
+                    let mut chunk_reader = bam::Reader::from_path(&chunk)
+                        .context("Failed to read temporary alignment file")?;
+                    let mut record = bam::Record::new();
+                    while let Some(result) = chunk_reader.read(&mut record) {
+                        result.context("Failed to read temporary alignment file")?;
+                        writer.write(&record).context("Failed to write record")?;
                     }
-                    regions_below_coverage = true;
                 }
-
-                // remove smallest end position from heap and update next_pos and n_reads_needed
-                // allowing for the fact that there may be multiple reads with the same end position
-                while let Some(Reverse((end, qname))) = heap.pop() {
-                    next_pos = end;
-
-                    if current_reads.contains(&qname) {
-                        current_reads.remove(&qname);
-                        n_reads_needed += 1;
-                    } else {
-                        return Err(anyhow!("A read in the heap was not found in the current reads set. This should not happen, please raise an issue."));
+            }
+        } else {
+            for (tid, intervals) in &chromosomes {
+                kept_per_chrom.extend(self.subsample_chromosome(
+                    &mut input,
+                    intervals,
+                    &depth_maps[*tid as usize],
+                    chrom_seeds[*tid as usize],
+                    &mut selection,
+                    &mut sink,
+                )?);
+            }
+        }
+        sink.finish()?;
+        let mut writer = sink.writer;
+        let Selection {
+            kept_records,
+            kept_templates,
+            mut spanning_records,
+        } = selection;
+
+        if let (true, Some(writer)) = (second_pass, writer.as_mut()) {
+            if self.annotates() {
+                info!("Writing all records, annotated with whether they were selected");
+            } else if self.keep_pairs {
+                info!("Writing all records of the selected templates");
+            } else {
+                info!("Writing the selected records and those that don't pass the filters");
+            }
+            // without regions, or when only writing selected templates - whose records can be
+            // anywhere - all records, including unmapped ones, are read. So is the whole of an
+            // unindexed input, skipping the records outside the regions
+            let all_records = !self.has_regions() || (self.keep_pairs && !self.annotates());
+            let fetch_all = all_records || matches!(input, Input::Stream(_));
+            if let Input::Stream(stream) = &mut input {
+                stream.reader = self.open_unindexed()?;
+                if let Some(tpool) = &tpool {
+                    input.set_thread_pool(tpool)?;
+                }
+            }
+            let fetches: Vec<Option<&Interval>> = if fetch_all {
+                vec![None]
+            } else {
+                intervals.iter().map(Some).collect()
+            };
+            spanning_records.clear();
+            let mut record = bam::Record::new();
+            for interval in fetches {
+                let mut keys = RecordKeys::default();
+                if let Input::Indexed(reader) = &mut input {
+                    match interval {
+                        Some(i) => {
+                            reader.fetch(FetchDefinition::Region(i.tid as i32, i.start, i.end))
+                        }
+                        None => reader.fetch(FetchDefinition::All),
                     }
-                    if heap.is_empty() {
-                        break;
+                    .context("Failed to fetch records")?;
+                }
+                while let Some(result) = input.read(&mut record) {
+                    result.context("Failed to parse record")?;
+                    let key = keys.key(&record);
+                    if !all_records && interval.is_none() && !overlaps_any(&intervals, &record) {
+                        continue;
                     }
-                    if let Some(Reverse((next_end, _))) = heap.peek() {
-                        if *next_end != end {
-                            break;
-                        }
+                    if spanning_records.contains(&key) {
+                        continue;
+                    }
+                    if interval.is_some_and(|i| record.reference_end() > i.end) {
+                        spanning_records.insert(key);
+                    }
+                    let keep = if self.keep_pairs {
+                        kept_templates.contains(record.qname())
                     } else {
-                        break;
+                        kept_records.contains(&key)
+                    };
+                    // filtered records are written unchanged, but only within the regions
+                    let passed_through = self.pass_filtered
+                        && !keep
+                        && !self.passes_filters(&record)
+                        && (interval.is_some()
+                            || !self.has_regions()
+                            || overlaps_any(&intervals, &record));
+                    if passed_through {
+                        writer.write(&record).context("Failed to write record")?;
+                        continue;
                     }
+                    if !self.annotates() && !keep {
+                        continue;
+                    }
+                    self.annotate(&mut record, keep)?;
+                    writer.write(&record).context("Failed to write record")?;
                 }
-
-                if next_pos as u64 >= chrom_len {
-                    break;
-                }
-            }
-            if regions_below_coverage {
-                warn!(
-                    "Chromosome {} has regions with less than the requested coverage",
-                    chrom_name
-                );
             }
         }
 
+        if self.dry_run {
+            write_dry_run_report(&kept_per_chrom, &mut stdout())
+                .context("Failed to write dry run report")?;
+        }
+
+        // the index is built from the output once it is complete, so the writer must be closed.
+        // htslib can also index while writing, but that needs the writer's file handle, which
+        // rust-htslib doesn't expose
+        drop(writer);
+        if let (true, Some(path)) = (self.write_index, &self.output) {
+            info!("Writing index for {:?}", path);
+            bam::index::build(path, None, index_type, self.threads)
+                .context(format!("Failed to write index for {:?}", path))?;
+        }
+
         Ok(())
     }
 }
 
+/// A copy of `header` whose @SQ lines without a UR field point to `reference`.
+fn with_reference_url(header: &bam::Header, reference: &Path) -> Result<bam::Header> {
+    let reference = reference
+        .canonicalize()
+        .context(format!("Failed to find reference {:?}", reference))?;
+    let url = format!("UR:{}", reference.to_string_lossy());
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let mut text: String = text
+        .lines()
+        .map(|line| {
+            if line.starts_with("@SQ") && !line.split('\t').any(|f| f.starts_with("UR:")) {
+                format!("{}\t{}\n", line, url)
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect();
+    if text.is_empty() {
+        text.push('\n');
+    }
+    Ok(bam::Header::from_template(&bam::HeaderView::from_bytes(
+        text.as_bytes(),
+    )))
+}
+
+/// The type of index to build for a BAM with `header` - BAI, unless a chromosome is longer than
+/// BAI supports (2^29 - 1), in which case CSI. CRAM files always get a CRAI index.
+fn index_type(header: &bam::HeaderView) -> bam::index::Type {
+    const BAI_MAX_LENGTH: u64 = (1 << 29) - 1;
+    let too_long = (0..header.target_count()).any(|tid| {
+        header
+            .target_len(tid)
+            .is_some_and(|len| len > BAI_MAX_LENGTH)
+    });
+    if too_long {
+        bam::index::Type::Csi(14)
+    } else {
+        bam::index::Type::Bai
+    }
+}
+
+/// Whether `record` overlaps any of `intervals`.
+fn overlaps_any(intervals: &[Interval], record: &bam::Record) -> bool {
+    intervals.iter().any(|i| {
+        i.tid as i32 == record.tid() && record.pos() < i.end && record.reference_end() > i.start
+    })
+}
+
+/// Parses samtools-style alignment flags - an integer, hex (e.g. 0x900) or a comma-separated list
+/// of flag names (e.g. UNMAP,SECONDARY).
+pub fn parse_flags(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).map_err(|e| format!("Invalid flags {}: {}", s, e));
+    }
+    if let Ok(flags) = s.parse::<u16>() {
+        return Ok(flags);
+    }
+    s.split(',').try_fold(0, |flags, name| {
+        let flag = match name.trim().to_ascii_uppercase().as_str() {
+            "PAIRED" => htslib::BAM_FPAIRED,
+            "PROPER_PAIR" => htslib::BAM_FPROPER_PAIR,
+            "UNMAP" => htslib::BAM_FUNMAP,
+            "MUNMAP" => htslib::BAM_FMUNMAP,
+            "REVERSE" => htslib::BAM_FREVERSE,
+            "MREVERSE" => htslib::BAM_FMREVERSE,
+            "READ1" => htslib::BAM_FREAD1,
+            "READ2" => htslib::BAM_FREAD2,
+            "SECONDARY" => htslib::BAM_FSECONDARY,
+            "QCFAIL" => htslib::BAM_FQCFAIL,
+            "DUP" => htslib::BAM_FDUP,
+            "SUPPLEMENTARY" => htslib::BAM_FSUPPLEMENTARY,
+            _ => return Err(format!("Invalid flag name {} in {}", name, s)),
+        };
+        Ok(flags | flag as u16)
+    })
+}
+
+/// Writes the number of reads that would be kept for each chromosome as a tab-separated table.
+fn write_dry_run_report<W: Write>(kept_per_chrom: &[(String, u64)], w: &mut W) -> io::Result<()> {
+    writeln!(w, "chrom\treads")?;
+    for (chrom, nb_kept) in kept_per_chrom {
+        writeln!(w, "{}\t{}", chrom, nb_kept)?;
+    }
+    Ok(())
+}
+
 /// Sorts the vector with a custom order where equal keys are randomly ordered.
-fn random_sort<T, K: Ord + Copy>(vec: &mut [T], key_extractor: fn(&T) -> K, mut rng: impl Rng) {
+fn random_sort<T, K: Ord + Copy>(
+    vec: &mut [T],
+    key_extractor: impl Fn(&T) -> K,
+    mut rng: impl Rng,
+) {
     vec.sort_by(|a, b| random_compare(key_extractor(a), key_extractor(b), &mut rng));
 }
 
@@ -387,12 +1619,17 @@ mod tests {
     }
 
     #[test]
-    fn bam_no_index_fails() {
+    fn unsorted_bam_without_index_fails() {
         let infile = "tests/cases/no_index.bam";
         let passed_args = vec![SUB, infile, "-c", "1"];
         let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
 
-        cmd.args(passed_args).assert().failure();
+        cmd.args(passed_args)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "must be sorted by coordinate, with SO:coordinate in the header",
+            ));
     }
 
     #[test]
@@ -403,4 +1640,744 @@ mod tests {
 
         cmd.args(passed_args).assert().success();
     }
+
+    #[test]
+    fn dry_run_reports_reads_per_chromosome() {
+        let infile = "tests/cases/no_start_end.bam";
+        let passed_args = vec![SUB, infile, "-c", "1", "-s", "1", "--dry-run"];
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+
+        cmd.args(passed_args)
+            .assert()
+            .success()
+            .stdout(predicates::str::starts_with("chrom\treads\nchromosome\t"))
+            .stdout(predicates::str::contains("plasmid_1\t0\n"));
+    }
+
+    #[test]
+    fn write_dry_run_report_as_table() {
+        let kept = vec![("chr1".to_string(), 10), ("chr2".to_string(), 0)];
+        let mut out: Vec<u8> = vec![];
+        write_dry_run_report(&kept, &mut out).unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = "chrom\treads\nchr1\t10\nchr2\t0\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn tag_and_qc_fail_annotate_every_record() {
+        let infile = "tests/cases/no_start_end.bam";
+        let dir = tempfile::tempdir().unwrap();
+        let subsampled = dir.path().join("sub.sam");
+        let tagged = dir.path().join("tagged.sam");
+        let args = [SUB, infile, "-c", "1", "-s", "1", "-o"];
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(args)
+            .arg(&subsampled)
+            .assert()
+            .success();
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(args)
+            .arg(&tagged)
+            .args(["--tag", "--qc-fail"])
+            .assert()
+            .success();
+
+        let mut reader = bam::Reader::from_path(infile).unwrap();
+        let nb_input = reader.records().count();
+        let mut reader = bam::Reader::from_path(&subsampled).unwrap();
+        let nb_subsampled = reader.records().count();
+        let mut reader = bam::Reader::from_path(&tagged).unwrap();
+        let records: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+        let kept: Vec<&bam::Record> = records
+            .iter()
+            .filter(|r| matches!(r.aux(KEEP_AUX_TAG), Ok(Aux::U8(1))))
+            .collect();
+
+        assert_eq!(records.len(), nb_input);
+        assert_eq!(kept.len(), nb_subsampled);
+        assert!(kept.iter().all(|r| !r.is_quality_check_failed()));
+        assert_eq!(
+            records
+                .iter()
+                .filter(|r| r.is_quality_check_failed())
+                .count(),
+            nb_input - nb_subsampled
+        );
+    }
+
+    #[test]
+    fn tag_marks_only_the_selected_copy_of_duplicated_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let seq = "A".repeat(50);
+        let qual = "I".repeat(50);
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n");
+        for _ in 0..3 {
+            sam.push_str(&format!(
+                "dup\t0\tchr1\t101\t60\t50M\t*\t0\t0\t{seq}\t{qual}\n"
+            ));
+        }
+        let infile = indexed_bam(dir.path(), "dups", &sam);
+        let tagged = dir.path().join("tagged.sam");
+
+        for regions in [
+            vec![],
+            vec!["--region", "chr1:1-110", "--region", "chr1:130-300"],
+        ] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .arg(SUB)
+                .arg(&infile)
+                .args(["-c", "1", "-s", "1", "--tag", "-o"])
+                .arg(&tagged)
+                .args(&regions)
+                .assert()
+                .success();
+
+            let mut reader = bam::Reader::from_path(&tagged).unwrap();
+            let kept: Vec<bool> = reader
+                .records()
+                .map(|r| matches!(r.unwrap().aux(KEEP_AUX_TAG), Ok(Aux::U8(1))))
+                .collect();
+            assert_eq!(kept.len(), 3, "{regions:?}");
+            assert_eq!(kept.iter().filter(|&&keep| keep).count(), 1, "{regions:?}");
+        }
+    }
+
+    #[test]
+    fn regions_are_merged_and_reported_separately() {
+        let infile = "tests/cases/no_start_end.bam";
+        let passed_args = vec![
+            SUB,
+            infile,
+            "-c",
+            "1",
+            "-s",
+            "1",
+            "--dry-run",
+            "--region",
+            "chromosome:500001-550000",
+            "--region",
+            "chromosome:540000-560000",
+            "--region",
+            "plasmid_1",
+        ];
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+
+        cmd.args(passed_args).assert().success().stdout(
+            predicates::str::is_match(
+                "^chrom\treads\nchromosome:500001-560000\t[1-9][0-9]*\nplasmid_1\t0\n$",
+            )
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn regions_file_restricts_output_to_regions() {
+        let infile = "tests/cases/no_start_end.bam";
+        let dir = tempfile::tempdir().unwrap();
+        let bed = dir.path().join("regions.bed");
+        std::fs::write(&bed, "chromosome\t600000\t610000\n").unwrap();
+        let output = dir.path().join("out.sam");
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args([SUB, infile, "-c", "2", "-s", "1", "--regions-file"])
+            .arg(&bed)
+            .arg("-o")
+            .arg(&output)
+            .assert()
+            .success();
+
+        let mut reader = bam::Reader::from_path(&output).unwrap();
+        let records: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+
+        assert!(!records.is_empty());
+        assert!(records
+            .iter()
+            .all(|r| r.pos() < 610_000 && r.reference_end() > 600_000));
+    }
+
+    #[test]
+    fn region_on_unknown_chromosome_raises_error() {
+        let infile = "tests/cases/no_start_end.bam";
+        let passed_args = vec![SUB, infile, "-c", "1", "--region", "chrX:1-100"];
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+
+        cmd.args(passed_args)
+            .assert()
+            .failure()
+            .stdout(predicates::str::is_empty())
+            .stderr(predicates::str::contains(
+                "Region chrX:1-100 is on a chromosome that is not in the alignment header",
+            ));
+    }
+
+    #[test]
+    fn depths_file_overrides_coverage_in_regions() {
+        let infile = "tests/cases/no_start_end.bam";
+        let dir = tempfile::tempdir().unwrap();
+        let kept_reads = |bed: &str| -> u64 {
+            let depths = dir.path().join("depths.bed");
+            std::fs::write(&depths, bed).unwrap();
+            let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .args([SUB, infile, "-c", "1", "-s", "1", "--dry-run", "--depths"])
+                .arg(&depths)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            let report = String::from_utf8(output.stdout).unwrap();
+            let line = report.lines().nth(1).unwrap();
+            line.split('\t').nth(1).unwrap().parse().unwrap()
+        };
+
+        let default = kept_reads("plasmid_1\t0\t10\t1\n");
+        let higher = kept_reads("chromosome\t600000\t700000\t3\n");
+        let none = kept_reads("chromosome\t0\t5399960\t0\n");
+
+        assert!(higher > default);
+        assert_eq!(none, 0);
+    }
+
+    /// Writes an indexed BAM of 20 read pairs on chr1, with mates 2kb apart, and a supplementary
+    /// alignment on chr2 for the first pair.
+    fn paired_bam(dir: &Path) -> PathBuf {
+        let seq = "A".repeat(50);
+        let qual = "I".repeat(50);
+        let mut sam = String::from(
+            "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:1000\n",
+        );
+        let mut lines = vec![];
+        for i in 0..20 {
+            let (pos1, pos2) = (101 + i * 10, 2001 + i * 10);
+            lines.push((
+                0,
+                pos1,
+                format!("pair{i}\t99\tchr1\t{pos1}\t60\t50M\t=\t{pos2}\t1950\t{seq}\t{qual}"),
+            ));
+            lines.push((
+                0,
+                pos2,
+                format!("pair{i}\t147\tchr1\t{pos2}\t60\t50M\t=\t{pos1}\t-1950\t{seq}\t{qual}"),
+            ));
+        }
+        lines.push((
+            1,
+            11,
+            format!("pair0\t2147\tchr2\t11\t60\t50M\tchr1\t2191\t0\t{seq}\t{qual}"),
+        ));
+        lines.sort();
+        for (_, _, line) in lines {
+            sam.push_str(&line);
+            sam.push('\n');
+        }
+        indexed_bam(dir, "paired", &sam)
+    }
+
+    /// Writes `sam` to an indexed BAM file called `name`.bam in `dir`.
+    fn indexed_bam(dir: &Path, name: &str, sam: &str) -> PathBuf {
+        let sam_path = dir.join(format!("{}.sam", name));
+        std::fs::write(&sam_path, sam).unwrap();
+
+        let bam_path = dir.join(format!("{}.bam", name));
+        let mut reader = bam::Reader::from_path(&sam_path).unwrap();
+        let header = bam::Header::from_template(reader.header());
+        {
+            let mut writer = bam::Writer::from_path(&bam_path, &header, Format::Bam).unwrap();
+            for record in reader.records() {
+                writer.write(&record.unwrap()).unwrap();
+            }
+        }
+        bam::index::build(&bam_path, None, bam::index::Type::Bai, 1).unwrap();
+        bam_path
+    }
+
+    /// The flags of the records in `path`, grouped by read name.
+    fn flags_by_name(path: &Path) -> std::collections::HashMap<Vec<u8>, Vec<u16>> {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let mut flags: std::collections::HashMap<Vec<u8>, Vec<u16>> = Default::default();
+        for record in reader.records() {
+            let record = record.unwrap();
+            flags
+                .entry(record.qname().to_vec())
+                .or_default()
+                .push(record.flags());
+        }
+        flags
+    }
+
+    #[test]
+    fn keep_pairs_writes_both_mates_and_supplementary() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = paired_bam(dir.path());
+        let output = dir.path().join("out.bam");
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, "-c", "3", "-s", "1", "--keep-pairs", "-o"])
+            .arg(&output)
+            .arg(&bam_path)
+            .assert()
+            .success();
+
+        let flags = flags_by_name(&output);
+
+        assert!(!flags.is_empty() && flags.len() < 20);
+        for (name, mut flags) in flags {
+            flags.sort_unstable();
+            if name == b"pair0" {
+                assert_eq!(flags, vec![99, 147, 2147]);
+            } else {
+                assert_eq!(flags, vec![99, 147]);
+            }
+        }
+    }
+
+    #[test]
+    fn without_keep_pairs_mates_are_selected_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let seq = "A".repeat(50);
+        let qual = "I".repeat(50);
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n");
+        // the first mates overlap, so only one of them is needed, but each second mate is the
+        // only record where it is
+        for i in 0..4 {
+            sam.push_str(&format!(
+                "pair{i}\t99\tchr1\t101\t60\t50M\t=\t{}\t0\t{seq}\t{qual}\n",
+                2001 + i * 100
+            ));
+        }
+        for i in 0..4 {
+            sam.push_str(&format!(
+                "pair{i}\t147\tchr1\t{}\t60\t50M\t=\t101\t0\t{seq}\t{qual}\n",
+                2001 + i * 100
+            ));
+        }
+        let bam_path = indexed_bam(dir.path(), "mates", &sam);
+        let output = dir.path().join("out.bam");
+
+        for seed in ["1", "2", "3"] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .args([SUB, "-c", "1", "-s", seed, "-o"])
+                .arg(&output)
+                .arg(&bam_path)
+                .assert()
+                .success();
+
+            let flags = flags_by_name(&output);
+
+            assert_eq!(flags.len(), 4, "seed {seed}");
+            assert_eq!(
+                flags.values().filter(|flags| flags.len() == 1).count(),
+                3,
+                "seed {seed}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_flags_accepts_integers_hex_and_names() {
+        assert_eq!(parse_flags("4").unwrap(), 4);
+        assert_eq!(parse_flags("0x900").unwrap(), 0x900);
+        assert_eq!(parse_flags("UNMAP,secondary").unwrap(), 0x104);
+        assert_eq!(parse_flags("PAIRED,PROPER_PAIR,DUP,QCFAIL").unwrap(), 0x603);
+        assert!(parse_flags("UNMAP,BOGUS").is_err());
+        assert!(parse_flags("0xZZ").is_err());
+    }
+
+    /// Subsamples the `paired_bam` to a depth beyond its coverage, so every record that passes
+    /// the filters is selected, and returns the flags of the output records.
+    fn flags_after_filtering(args: &[&str]) -> Vec<u16> {
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = paired_bam(dir.path());
+        let output = dir.path().join("out.bam");
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, "-c", "100", "-s", "1", "-o"])
+            .arg(&output)
+            .args(args)
+            .arg(&bam_path)
+            .assert()
+            .success();
+
+        let mut flags: Vec<u16> = flags_by_name(&output).into_values().flatten().collect();
+        flags.sort_unstable();
+        flags
+    }
+
+    #[test]
+    fn flag_filters_select_matching_records() {
+        let flags = flags_after_filtering(&[]);
+        assert_eq!(flags.len(), 41);
+
+        let flags = flags_after_filtering(&["--exclude-flags", "SUPPLEMENTARY"]);
+        assert_eq!(flags.len(), 40);
+        assert!(!flags.contains(&2147));
+
+        let flags = flags_after_filtering(&["--require-flags", "0x80"]);
+        assert_eq!(flags, vec![147; 20]);
+    }
+
+    #[test]
+    fn min_mapq_and_min_aligned_length_filter_records() {
+        assert!(flags_after_filtering(&["--min-mapq", "61"]).is_empty());
+        assert_eq!(flags_after_filtering(&["--min-mapq", "60"]).len(), 41);
+        assert!(flags_after_filtering(&["--min-aligned-length", "51"]).is_empty());
+        assert_eq!(
+            flags_after_filtering(&["--min-aligned-length", "50"]).len(),
+            41
+        );
+    }
+
+    #[test]
+    fn pass_filtered_writes_filtered_records_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = paired_bam(dir.path());
+        let output = dir.path().join("out.bam");
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, "-c", "1", "-s", "1", "--exclude-flags", "READ2"])
+            .args(["--pass-filtered", "-o"])
+            .arg(&output)
+            .arg(&bam_path)
+            .assert()
+            .success();
+
+        let flags: Vec<u16> = flags_by_name(&output).into_values().flatten().collect();
+        // every READ2 record is written, while only some of the others are selected
+        assert_eq!(flags.iter().filter(|&&f| f == 147).count(), 20);
+        let selected = flags.iter().filter(|&&f| f != 147).count();
+        assert!(selected > 0 && selected < 21);
+    }
+
+    /// The header sort order and the (tid, pos, name) of each record in `path`.
+    fn sort_order_and_records(path: &Path) -> (String, Vec<(i32, i64, Vec<u8>)>) {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let header = String::from_utf8(reader.header().as_bytes().to_vec()).unwrap();
+        let sort_order = header
+            .lines()
+            .next()
+            .and_then(|hd| hd.split('\t').find_map(|f| f.strip_prefix("SO:")))
+            .unwrap()
+            .to_string();
+        let records = reader
+            .records()
+            .map(|r| r.unwrap())
+            .map(|r| (r.tid(), r.pos(), r.qname().to_vec()))
+            .collect();
+        (sort_order, records)
+    }
+
+    #[test]
+    fn output_is_sorted_by_default() {
+        let infile = "tests/cases/no_start_end.bam";
+        let dir = tempfile::tempdir().unwrap();
+        let sorted = dir.path().join("sorted.bam");
+        let unsorted = dir.path().join("unsorted.bam");
+        let args = [SUB, infile, "-c", "5", "-s", "1", "-o"];
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(args)
+            .arg(&sorted)
+            .assert()
+            .success();
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(args)
+            .arg(&unsorted)
+            .args(["--sort", "false"])
+            .assert()
+            .success();
+
+        let (sort_order, records) = sort_order_and_records(&sorted);
+        assert_eq!(sort_order, "coordinate");
+        assert!(records
+            .windows(2)
+            .all(|w| (w[0].0, w[0].1) <= (w[1].0, w[1].1)));
+
+        // the same records are selected either way
+        let (sort_order, mut unsorted_records) = sort_order_and_records(&unsorted);
+        assert_eq!(sort_order, "unsorted");
+        unsorted_records.sort();
+        let mut records = records;
+        records.sort();
+        assert_eq!(records, unsorted_records);
+    }
+
+    #[test]
+    fn write_index_writes_usable_index() {
+        let infile = "tests/cases/no_start_end.bam";
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bam");
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, infile, "-c", "1", "-s", "1", "--write-index", "-o"])
+            .arg(&output)
+            .assert()
+            .success();
+
+        assert!(dir.path().join("out.bam.bai").exists());
+        let mut reader = bam::IndexedReader::from_path(&output).unwrap();
+        reader.fetch("chromosome").unwrap();
+        assert_eq!(reader.records().count(), 12);
+    }
+
+    #[test]
+    fn write_index_needs_sorted_bam_or_cram() {
+        let infile = "tests/cases/no_start_end.bam";
+        let dir = tempfile::tempdir().unwrap();
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, infile, "-c", "1", "--write-index", "-o"])
+            .arg(dir.path().join("out.sam"))
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("needs BAM or CRAM output"));
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([
+                SUB,
+                infile,
+                "-c",
+                "1",
+                "--write-index",
+                "--sort",
+                "false",
+                "-o",
+            ])
+            .arg(dir.path().join("out.bam"))
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("needs sorted output"));
+        // stdout can't be indexed
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, infile, "-c", "1", "--write-index"])
+            .assert()
+            .failure();
+    }
+
+    #[test]
+    fn index_type_is_csi_for_long_chromosomes() {
+        let header = bam::HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+        assert!(matches!(index_type(&header), bam::index::Type::Bai));
+
+        let header =
+            bam::HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:600000000\n");
+        assert!(matches!(index_type(&header), bam::index::Type::Csi(14)));
+    }
+
+    #[test]
+    fn reference_is_used_to_read_and_write_cram() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = paired_bam(dir.path());
+        let reference = dir.path().join("ref.fa");
+        let fasta = format!(
+            ">chr1\n{}\n>chr2\n{}\n",
+            "A".repeat(10000),
+            "A".repeat(1000)
+        );
+        std::fs::write(&reference, fasta).unwrap();
+        let cram = dir.path().join("out.cram");
+        let bam = dir.path().join("out.bam");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, "-c", "100", "--write-index", "--reference"])
+            .arg(&reference)
+            .arg("-o")
+            .arg(&cram)
+            .arg(&bam_path)
+            .assert()
+            .success();
+        assert!(dir.path().join("out.cram.crai").exists());
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, "-c", "100", "--reference"])
+            .arg(&reference)
+            .arg("-o")
+            .arg(&bam)
+            .arg(&cram)
+            .assert()
+            .success();
+        let mut reader = bam::Reader::from_path(&bam).unwrap();
+        assert_eq!(reader.records().count(), 41);
+
+        // without the reference, and nowhere for htslib to find it, the error says what to do
+        std::fs::remove_file(&reference).unwrap();
+        let _ = std::fs::remove_file(dir.path().join("ref.fa.fai"));
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .env("REF_PATH", dir.path().join("missing"))
+            .env("REF_CACHE", dir.path().join("cache"))
+            .args([SUB, "-c", "100", "-o"])
+            .arg(&bam)
+            .arg(&cram)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("pass it with --reference"));
+    }
+
+    #[test]
+    fn output_is_the_same_for_any_number_of_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = paired_bam(dir.path());
+        let subsample = |args: &[&str]| {
+            let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .args([SUB, "-c", "3", "-s", "7", "-O", "s"])
+                .args(args)
+                .arg(&bam_path)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        for args in [&[][..], &["--sort", "false"], &["--tag"], &["--dry-run"]] {
+            let expected = subsample(args);
+            assert!(expected.lines().any(|l| !l.starts_with('@')));
+            for threads in ["2", "4"] {
+                let actual = subsample(&[args, &["--threads", threads]].concat());
+                assert_eq!(actual, expected, "{:?} with {} threads", args, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn deep_pileups_are_capped_and_gaps_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let seq = "A".repeat(50);
+        let qual = "I".repeat(50);
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000000\n");
+        // a 2000x amplicon, then a single read far along the chromosome
+        for i in 0..2000 {
+            sam.push_str(&format!(
+                "amp{i}\t0\tchr1\t101\t60\t50M\t*\t0\t0\t{seq}\t{qual}\n"
+            ));
+        }
+        sam.push_str(&format!(
+            "far\t0\tchr1\t900001\t60\t50M\t*\t0\t0\t{seq}\t{qual}\n"
+        ));
+        let bam_path = indexed_bam(dir.path(), "amplicon", &sam);
+
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args([SUB, "-c", "10", "-s", "1", "-O", "s"])
+            .arg(&bam_path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let names: Vec<String> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter(|l| !l.starts_with('@'))
+            .map(|l| l.split('\t').next().unwrap().to_string())
+            .collect();
+
+        assert_eq!(names.iter().filter(|n| n.starts_with("amp")).count(), 10);
+        assert_eq!(names.last().map(String::as_str), Some("far"));
+    }
+
+    #[test]
+    fn unindexed_and_piped_input_match_indexed_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let indexed = paired_bam(dir.path());
+        let unindexed = dir.path().join("unindexed.bam");
+        std::fs::copy(&indexed, &unindexed).unwrap();
+        let subsample = |input: &Path, args: &[&str]| {
+            let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .args([SUB, "-c", "3", "-s", "7", "-O", "s"])
+                .args(args)
+                .arg(input)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let regions = [
+            "--region",
+            "chr1:1-500",
+            "--region",
+            "chr1:400-1500",
+            "--region",
+            "chr1:2000-2500",
+            "--region",
+            "chr2",
+        ];
+
+        for args in [
+            &[][..],
+            &["--sort", "false"],
+            &regions,
+            &["--tag"],
+            &["--keep-pairs"],
+            &[&regions[..], &["--tag"]].concat(),
+            &[&regions[..], &["--keep-pairs"]].concat(),
+            &["--dry-run", "--threads", "2"],
+        ] {
+            let expected = subsample(&indexed, args);
+            assert!(expected.lines().any(|l| !l.starts_with('@')));
+            assert_eq!(subsample(&unindexed, args), expected, "{:?}", args);
+        }
+
+        for args in [&[][..], &regions] {
+            let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .args([SUB, "-c", "3", "-s", "7", "-O", "s"])
+                .args(args)
+                .arg("-")
+                .pipe_stdin(&indexed)
+                .unwrap()
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            let actual = String::from_utf8(output.stdout).unwrap();
+            assert_eq!(actual, subsample(&indexed, args), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn piped_input_that_needs_a_second_pass_fails() {
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args([SUB, "-c", "3", "--tag", "-"])
+            .pipe_stdin("tests/cases/no_start_end.bam")
+            .unwrap();
+
+        cmd.assert()
+            .failure()
+            .stderr(predicates::str::contains("can't read it from stdin"));
+    }
+
+    #[test]
+    fn unindexed_input_must_be_sorted() {
+        let seq = "A".repeat(50);
+        let qual = "I".repeat(50);
+        let records = format!(
+            "r1\t0\tchr1\t501\t60\t50M\t*\t0\t0\t{seq}\t{qual}\nr2\t0\tchr1\t101\t60\t50M\t*\t0\t0\t{seq}\t{qual}\n"
+        );
+        let subsample = |sort_order: &str| {
+            let sam = format!(
+                "@HD\tVN:1.6\tSO:{}\n@SQ\tSN:chr1\tLN:1000\n{}",
+                sort_order, records
+            );
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .args([SUB, "-c", "3", "-O", "s", "-"])
+                .write_stdin(sam)
+                .assert()
+                .failure()
+        };
+
+        subsample("unsorted").stderr(predicates::str::contains(
+            "must be sorted by coordinate, with SO:coordinate in the header",
+        ));
+        subsample("coordinate").stderr(predicates::str::contains(
+            "not sorted by coordinate - r2 at chr1:101 comes after a record at chr1:501",
+        ));
+    }
 }
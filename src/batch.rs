@@ -0,0 +1,716 @@
+use std::fs::File;
+use std::io::{stdout, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::{error, info, warn};
+use thiserror::Error;
+
+use crate::cli::{check_path_exists, Coverage, GenomeSize};
+use crate::reads::{combined_read_lengths, Reads, ReadsSummary};
+use crate::utils::par_map;
+use crate::Runner;
+
+/// Columns of the sample sheet that are passed on to `rasusa reads`, along with the option they
+/// map to.
+const OPTION_COLUMNS: &[(&str, &str)] = &[
+    ("genome_size", "--genome-size"),
+    ("coverage", "--coverage"),
+    ("bases", "--bases"),
+    ("num", "--num"),
+    ("frac", "--frac"),
+    ("seed", "--seed"),
+];
+
+/// A collection of custom errors relating to the sample sheet.
+#[derive(Error, Debug, PartialEq)]
+pub enum BatchError {
+    /// Indicates the sample sheet is missing a required column.
+    #[error("Sample sheet is missing the required column {0}")]
+    MissingColumn(String),
+
+    /// Indicates the sample sheet has a column that is not recognised.
+    #[error("Sample sheet has an unknown column {0}")]
+    UnknownColumn(String),
+
+    /// Indicates a row of the sample sheet could not be parsed.
+    #[error("Line {line} of the sample sheet: {msg}")]
+    InvalidRow { line: usize, msg: String },
+
+    /// Indicates the sample sheet has no samples.
+    #[error("Sample sheet has no samples")]
+    Empty,
+
+    /// Indicates a string cannot be parsed into a [`CohortTarget`](#cohorttarget).
+    #[error("{0} is not a valid cohort target. Use min, a percentile such as p10, or a coverage such as 30x")]
+    InvalidCohortTarget(String),
+
+    /// Indicates the sample sheet sets its own target, which conflicts with cohort mode.
+    #[error("The {0} column can't be used with --cohort-target as every sample is subsampled to the same coverage")]
+    CohortTargetConflict(String),
+
+    /// Indicates that no samples are left to determine the cohort target from.
+    #[error("No samples are left to determine the cohort target coverage from")]
+    NoCohortSamples,
+}
+
+/// How the shared coverage of a cohort is chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CohortTarget {
+    /// The lowest input coverage of any (non-excluded) sample.
+    Min,
+    /// The given percentile, 0-100, of the input coverages of the (non-excluded) samples.
+    Percentile(f64),
+    /// A fixed coverage.
+    Fixed(Coverage),
+}
+
+impl FromStr for CohortTarget {
+    type Err = BatchError;
+
+    /// Parses `min`, a percentile as `p<0-100>` (e.g., p10), or a coverage (e.g., 30x).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || BatchError::InvalidCohortTarget(s.to_string());
+        let lower = s.trim().to_lowercase();
+        if lower == "min" {
+            return Ok(CohortTarget::Min);
+        }
+        if let Some(pct) = lower.strip_prefix('p') {
+            return match pct.parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(CohortTarget::Percentile(p)),
+                _ => Err(err()),
+            };
+        }
+        match lower.parse::<Coverage>() {
+            Ok(covg) if covg.0 > 0.0 => Ok(CohortTarget::Fixed(covg)),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl CohortTarget {
+    /// The target coverage for samples with the input `coverages`. Returns `None` if there are no
+    /// coverages to pick from.
+    pub fn resolve(&self, coverages: &[f64]) -> Option<f64> {
+        let pct = match self {
+            CohortTarget::Fixed(covg) => return Some(f64::from(covg.0)),
+            CohortTarget::Min => 0.0,
+            CohortTarget::Percentile(p) => *p,
+        };
+        if coverages.is_empty() {
+            return None;
+        }
+        let mut sorted = coverages.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        // nearest-rank percentile
+        let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.max(1) - 1])
+    }
+}
+
+/// Subsample many samples, as listed in a sample sheet
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+pub struct Batch {
+    /// Sample sheet listing the samples to subsample
+    ///
+    /// A TSV (or CSV, if the file ends in .csv) file with a header row. The sample, r1 and out1
+    /// columns are required. The optional r2 and out2 columns give the second file of paired reads,
+    /// and the optional genome_size, coverage, bases, num, frac and seed columns take the same
+    /// values as the options of the same name in `rasusa reads`. Empty cells are ignored
+    #[arg(value_parser = check_path_exists, name = "SHEET")]
+    pub sheet: PathBuf,
+
+    /// Number of samples to subsample in parallel
+    #[arg(short, long, default_value_t = 1, value_name = "INT", value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: u16,
+
+    /// Write the report to this file. Defaults to stdout
+    #[arg(short, long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Random seed to use for samples without a seed column in the sample sheet
+    #[arg(short, long, value_name = "INT")]
+    pub seed: Option<u64>,
+
+    /// Subsample every sample to the same coverage: min, a percentile (e.g., p10), or a coverage
+    ///
+    /// The input coverage of each sample is calculated from its genome_size column. With min or a
+    /// percentile, the target is taken from the input coverages of the samples not excluded by
+    /// --cohort-floor. Samples below the target keep all their reads and are flagged in the report
+    #[arg(long, value_name = "min|pN|FLOAT")]
+    pub cohort_target: Option<CohortTarget>,
+
+    /// Exclude samples with an input coverage below this from the cohort
+    #[arg(long, value_name = "FLOAT", requires = "cohort_target")]
+    pub cohort_floor: Option<Coverage>,
+}
+
+/// A single row of the sample sheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub id: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// `rasusa reads` options, and their values, for this sample.
+    pub options: Vec<(&'static str, String)>,
+}
+
+impl Sample {
+    /// The `rasusa reads` arguments to subsample this sample with.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = self.inputs.clone();
+        for output in &self.outputs {
+            args.extend(["-o".to_string(), output.clone()]);
+        }
+        for (option, value) in &self.options {
+            args.extend([option.to_string(), value.clone()]);
+        }
+        args
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The number of reads in this sample's inputs, and their total number of bases, counted as
+    /// `rasusa reads` does.
+    pub fn input_size(&self) -> Result<(usize, u64)> {
+        let inputs: Vec<PathBuf> = self.inputs.iter().map(PathBuf::from).collect();
+        let lengths = combined_read_lengths(&inputs, |_| true)?;
+        let nb_bases = lengths.iter().map(|&l| u64::from(l)).sum();
+        Ok((lengths.len(), nb_bases))
+    }
+
+    /// The coverage of `nb_bases` of this sample with respect to its genome size.
+    pub fn input_coverage(&self, nb_bases: u64) -> Result<f64> {
+        let gsize: GenomeSize = self
+            .option("--genome-size")
+            .ok_or_else(|| anyhow!("a genome_size is required to calculate coverage"))?
+            .parse()?;
+        Ok(nb_bases as f64 / f64::from(gsize))
+    }
+}
+
+/// What happened to a single sample.
+#[derive(Debug)]
+pub enum Outcome {
+    Subsampled(ReadsSummary),
+    /// The sample was excluded from the cohort for the given reason.
+    Excluded(String),
+    Failed(anyhow::Error),
+}
+
+/// The outcome of subsampling a single sample.
+#[derive(Debug)]
+pub struct SampleResult {
+    pub id: String,
+    /// Only calculated in cohort mode.
+    pub input_coverage: Option<f64>,
+    pub outcome: Outcome,
+}
+
+impl Batch {
+    /// The field delimiter of the sample sheet - comma for .csv files, otherwise tab.
+    fn delimiter(&self) -> char {
+        match self.sheet.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => ',',
+            _ => '\t',
+        }
+    }
+
+    /// Parses the sample sheet into the samples to subsample.
+    pub fn samples(&self) -> Result<Vec<Sample>> {
+        let file = File::open(&self.sheet)
+            .context(format!("Failed to open sample sheet {:?}", self.sheet))?;
+        parse_sample_sheet(BufReader::new(file), self.delimiter(), self.seed)
+    }
+
+    /// Applies `f` to every item, using up to `--jobs` threads. The results are in the same order
+    /// as `items`.
+    fn par_map<I, T, F>(&self, items: &[I], f: F) -> Vec<T>
+    where
+        I: Sync,
+        T: Send,
+        F: Fn(&I) -> T + Sync,
+    {
+        par_map(items, usize::from(self.jobs), f)
+    }
+
+    /// Subsamples every sample. A failure for one sample does not stop the others.
+    pub fn subsample_all(&self, samples: &[Sample]) -> Vec<SampleResult> {
+        self.par_map(samples, |sample| SampleResult {
+            id: sample.id.clone(),
+            input_coverage: None,
+            outcome: subsample_sample(sample, &[]),
+        })
+    }
+
+    /// Calculates the input coverage of every sample, determines the shared target coverage from
+    /// `target`, and subsamples every sample that is not excluded to it.
+    pub fn subsample_cohort(
+        &self,
+        samples: &[Sample],
+        target: CohortTarget,
+    ) -> Result<Vec<SampleResult>> {
+        let conflicting = ["--coverage", "--bases", "--num", "--frac"];
+        for sample in samples {
+            if let Some((option, _)) = sample
+                .options
+                .iter()
+                .find(|(option, _)| conflicting.contains(option))
+            {
+                let col = option.trim_start_matches("--").to_string();
+                return Err(BatchError::CohortTargetConflict(col).into());
+            }
+        }
+
+        info!("Calculating the input coverage of each sample...");
+        // only the size of each sample is kept, so memory doesn't grow with the number of
+        // samples - their read lengths are gathered again to subsample them
+        let coverages = self.par_map(samples, |sample| {
+            let covg = sample.input_size().and_then(|(nb_reads, nb_bases)| {
+                let covg = sample.input_coverage(nb_bases)?;
+                info!(
+                    "Sample {} has {} reads and an input coverage of {:.2}x",
+                    sample.id, nb_reads, covg
+                );
+                Ok(covg)
+            });
+            if let Err(e) = &covg {
+                error!("Sample {} failed: {:#}", sample.id, e);
+            }
+            covg
+        });
+
+        let floor = self.cohort_floor.map(|c| f64::from(c.0));
+        let is_included = |covg: f64| covg >= floor.unwrap_or(0.0);
+        let included: Vec<f64> = coverages
+            .iter()
+            .filter_map(|c| c.as_ref().ok().copied())
+            .filter(|&c| is_included(c))
+            .collect();
+        let target_covg = target
+            .resolve(&included)
+            .ok_or(BatchError::NoCohortSamples)?;
+        info!("Cohort target coverage is {:.2}x", target_covg);
+
+        let jobs: Vec<(&Sample, Result<f64>)> = samples.iter().zip(coverages).collect();
+        let results = self.par_map(&jobs, |(sample, covg)| {
+            let outcome = match covg {
+                Err(e) => Outcome::Failed(anyhow!("{:#}", e)),
+                Ok(covg) if !is_included(*covg) => {
+                    let reason = format!(
+                        "input coverage {:.2}x is below the floor of {:.2}x",
+                        covg,
+                        floor.unwrap_or_default()
+                    );
+                    warn!("Sample {} excluded: {}", sample.id, reason);
+                    Outcome::Excluded(reason)
+                }
+                Ok(_) => subsample_sample(sample, &["--coverage", &target_covg.to_string()]),
+            };
+            SampleResult {
+                id: sample.id.clone(),
+                input_coverage: covg.as_ref().ok().copied(),
+                outcome,
+            }
+        });
+        Ok(results)
+    }
+}
+
+impl Runner for Batch {
+    fn run(&mut self) -> Result<()> {
+        let samples = self.samples()?;
+        info!(
+            "{} samples in sample sheet. Subsampling with {} job(s)...",
+            samples.len(),
+            self.jobs
+        );
+
+        let results = match self.cohort_target {
+            Some(target) => self.subsample_cohort(&samples, target)?,
+            None => self.subsample_all(&samples),
+        };
+
+        let mut report: Box<dyn Write> = match &self.report {
+            Some(path) => {
+                Box::new(File::create(path).context(format!("Failed to create report {:?}", path))?)
+            }
+            None => Box::new(stdout()),
+        };
+        write_report(&results, &mut report).context("Failed to write report")?;
+
+        let nb_failed = results
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Failed(_)))
+            .count();
+        if nb_failed > 0 {
+            return Err(anyhow!("{} of {} samples failed", nb_failed, results.len()));
+        }
+
+        info!("Done 🎉");
+        Ok(())
+    }
+}
+
+/// Subsamples `sample` with `extra_args` added to its `rasusa reads` arguments.
+fn subsample_sample(sample: &Sample, extra_args: &[&str]) -> Outcome {
+    info!("Subsampling sample {}...", sample.id);
+    let sample_args = sample.args();
+    let args = std::iter::once("reads")
+        .chain(sample_args.iter().map(String::as_str))
+        .chain(extra_args.iter().copied());
+    // only keep the first line of clap's error as the rest is usage help
+    let result = Reads::try_parse_from(args)
+        .map_err(|e| {
+            let msg = e.to_string();
+            let first_line = msg.lines().next().unwrap_or_default();
+            anyhow!(first_line.trim_start_matches("error: ").to_string())
+        })
+        .and_then(|reads| reads.subsample());
+    match result {
+        Ok(summary) => Outcome::Subsampled(summary),
+        Err(e) => {
+            error!("Sample {} failed: {:#}", sample.id, e);
+            Outcome::Failed(e)
+        }
+    }
+}
+
+/// Parses a sample sheet with a header row from `reader`. `seed` is used for samples without a
+/// seed of their own.
+fn parse_sample_sheet<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    seed: Option<u64>,
+) -> Result<Vec<Sample>> {
+    let mut lines = reader
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| match line {
+            Ok(l) => !l.trim().is_empty() && !l.starts_with('#'),
+            Err(_) => true,
+        });
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, line)) => line
+            .context("Failed to read sample sheet")?
+            .split(delimiter)
+            .map(|c| c.trim().to_lowercase())
+            .collect(),
+        None => return Err(BatchError::Empty.into()),
+    };
+    let column = |name: &str| header.iter().position(|c| c == name);
+    for col in &header {
+        let known = ["sample", "r1", "r2", "out1", "out2"].contains(&col.as_str())
+            || OPTION_COLUMNS.iter().any(|(c, _)| c == col);
+        if !known {
+            return Err(BatchError::UnknownColumn(col.to_string()).into());
+        }
+    }
+    let required = |name: &str| column(name).ok_or_else(|| BatchError::MissingColumn(name.into()));
+    let (sample_col, r1_col, out1_col) = (required("sample")?, required("r1")?, required("out1")?);
+
+    let mut samples = vec![];
+    for (line_nb, line) in lines {
+        let line = line.context("Failed to read sample sheet")?;
+        let fields: Vec<&str> = line.split(delimiter).map(str::trim).collect();
+        if fields.len() != header.len() {
+            return Err(BatchError::InvalidRow {
+                line: line_nb,
+                msg: format!("expected {} fields, got {}", header.len(), fields.len()),
+            }
+            .into());
+        }
+        let field = |col: Option<usize>| col.map(|i| fields[i]).filter(|f| !f.is_empty());
+
+        let id = field(Some(sample_col)).ok_or_else(|| BatchError::InvalidRow {
+            line: line_nb,
+            msg: String::from("sample is empty"),
+        })?;
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        for (input_col, output_col) in [
+            (Some(r1_col), Some(out1_col)),
+            (column("r2"), column("out2")),
+        ] {
+            match (field(input_col), field(output_col)) {
+                (Some(input), Some(output)) => {
+                    inputs.push(input.to_string());
+                    outputs.push(output.to_string());
+                }
+                (None, None) => {}
+                _ => {
+                    return Err(BatchError::InvalidRow {
+                        line: line_nb,
+                        msg: format!("sample {} needs both an input and an output", id),
+                    }
+                    .into())
+                }
+            }
+        }
+        let mut options = vec![];
+        for (col, option) in OPTION_COLUMNS {
+            if let Some(value) = field(column(col)) {
+                options.push((*option, value.to_string()));
+            }
+        }
+        if field(column("seed")).is_none() {
+            if let Some(seed) = seed {
+                options.push(("--seed", seed.to_string()));
+            }
+        }
+
+        samples.push(Sample {
+            id: id.to_string(),
+            inputs,
+            outputs,
+            options,
+        });
+    }
+
+    if samples.is_empty() {
+        return Err(BatchError::Empty.into());
+    }
+    Ok(samples)
+}
+
+/// Writes one tab-separated line per sample with what was kept, or the reason it was not.
+fn write_report<W: Write>(results: &[SampleResult], w: &mut W) -> std::io::Result<()> {
+    writeln!(
+        w,
+        "sample\tstatus\tinput_coverage\treads\tbases\tcoverage\tachievable\treason"
+    )?;
+    let fmt_covg = |covg: Option<f64>| covg.map(|c| format!("{:.2}", c)).unwrap_or_default();
+    for result in results {
+        let input_covg = fmt_covg(result.input_coverage);
+        match &result.outcome {
+            Outcome::Subsampled(summary) => writeln!(
+                w,
+                "{}\tok\t{}\t{}\t{}\t{}\t{}\t",
+                result.id,
+                input_covg,
+                summary.reads,
+                summary.bases,
+                fmt_covg(summary.coverage),
+                summary.achievable
+            )?,
+            Outcome::Excluded(reason) => writeln!(
+                w,
+                "{}\texcluded\t{}\t\t\t\t\t{}",
+                result.id, input_covg, reason
+            )?,
+            Outcome::Failed(e) => {
+                let msg = format!("{:#}", e).replace(['\t', '\n'], " ");
+                writeln!(w, "{}\tfailed\t{}\t\t\t\t\t{}", result.id, input_covg, msg)?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reads::input_read_lengths;
+    use std::path::Path;
+
+    fn parse(sheet: &str, delimiter: char) -> Result<Vec<Sample>> {
+        parse_sample_sheet(sheet.as_bytes(), delimiter, None)
+    }
+
+    #[test]
+    fn parse_single_end_sample() {
+        let sheet = "sample\tr1\tout1\tgenome_size\tcoverage\ns1\tin.fq\tout.fq\t5mb\t30\n";
+
+        let actual = parse(sheet, '\t').unwrap();
+        let expected = vec![Sample {
+            id: "s1".to_string(),
+            inputs: vec!["in.fq".to_string()],
+            outputs: vec!["out.fq".to_string()],
+            options: vec![
+                ("--genome-size", "5mb".to_string()),
+                ("--coverage", "30".to_string()),
+            ],
+        }];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn parse_paired_and_single_samples_csv() {
+        let sheet =
+            "sample,r1,r2,out1,out2,num\ns1,a1.fq,a2.fq,o1.fq,o2.fq,10\n\ns2,b1.fq,,p1.fq,,5\n";
+
+        let actual = parse(sheet, ',').unwrap();
+
+        assert_eq!(actual.len(), 2);
+        assert_eq!(
+            actual[0].args(),
+            vec!["a1.fq", "a2.fq", "-o", "o1.fq", "-o", "o2.fq", "--num", "10"]
+        );
+        assert_eq!(actual[1].args(), vec!["b1.fq", "-o", "p1.fq", "--num", "5"])
+    }
+
+    #[test]
+    fn parse_uses_default_seed_unless_given() {
+        let sheet =
+            "sample\tr1\tout1\tnum\tseed\ns1\tin.fq\tout.fq\t3\t\ns2\tin.fq\tout.fq\t3\t9\n";
+
+        let actual = parse_sample_sheet(sheet.as_bytes(), '\t', Some(1)).unwrap();
+
+        assert!(actual[0]
+            .args()
+            .ends_with(&["--seed".to_string(), "1".to_string()]));
+        assert!(actual[1]
+            .args()
+            .ends_with(&["--seed".to_string(), "9".to_string()]))
+    }
+
+    #[test]
+    fn parse_missing_required_column() {
+        let sheet = "sample\tr1\ns1\tin.fq\n";
+
+        let actual = parse(sheet, '\t').unwrap_err();
+
+        assert_eq!(
+            actual.downcast::<BatchError>().unwrap(),
+            BatchError::MissingColumn("out1".to_string())
+        )
+    }
+
+    #[test]
+    fn parse_unknown_column() {
+        let sheet = "sample\tr1\tout1\tcov\ns1\tin.fq\tout.fq\t3\n";
+
+        let actual = parse(sheet, '\t').unwrap_err();
+
+        assert_eq!(
+            actual.downcast::<BatchError>().unwrap(),
+            BatchError::UnknownColumn("cov".to_string())
+        )
+    }
+
+    #[test]
+    fn parse_input_without_output() {
+        let sheet = "sample\tr1\tr2\tout1\tout2\ns1\tin.fq\tin2.fq\tout.fq\t\n";
+
+        let actual = parse(sheet, '\t').unwrap_err();
+
+        assert!(matches!(
+            actual.downcast::<BatchError>().unwrap(),
+            BatchError::InvalidRow { line: 2, .. }
+        ))
+    }
+
+    #[test]
+    fn parse_header_only_is_empty() {
+        let sheet = "sample\tr1\tout1\n";
+
+        let actual = parse(sheet, '\t').unwrap_err();
+
+        assert_eq!(actual.downcast::<BatchError>().unwrap(), BatchError::Empty)
+    }
+
+    #[test]
+    fn cohort_target_from_str() {
+        assert_eq!("min".parse::<CohortTarget>(), Ok(CohortTarget::Min));
+        assert_eq!(
+            "P10".parse::<CohortTarget>(),
+            Ok(CohortTarget::Percentile(10.0))
+        );
+        assert_eq!(
+            "30x".parse::<CohortTarget>(),
+            Ok(CohortTarget::Fixed(Coverage(30.0)))
+        );
+        assert!("p101".parse::<CohortTarget>().is_err());
+        assert!("0".parse::<CohortTarget>().is_err());
+        assert!("mean".parse::<CohortTarget>().is_err());
+    }
+
+    #[test]
+    fn cohort_target_resolve() {
+        let coverages = [40.0, 10.0, 30.0, 20.0];
+
+        assert_eq!(CohortTarget::Min.resolve(&coverages), Some(10.0));
+        assert_eq!(
+            CohortTarget::Percentile(50.0).resolve(&coverages),
+            Some(20.0)
+        );
+        assert_eq!(
+            CohortTarget::Percentile(100.0).resolve(&coverages),
+            Some(40.0)
+        );
+        assert_eq!(
+            CohortTarget::Fixed(Coverage(5.0)).resolve(&coverages),
+            Some(5.0)
+        );
+        assert_eq!(CohortTarget::Min.resolve(&[]), None);
+    }
+
+    #[test]
+    fn sample_input_coverage() {
+        let sample = Sample {
+            id: "s1".to_string(),
+            inputs: vec!["tests/cases/r1.fq.gz".to_string()],
+            outputs: vec!["out.fq".to_string()],
+            options: vec![("--genome-size", "4".to_string())],
+        };
+
+        let (nb_reads, nb_bases) = sample.input_size().unwrap();
+        let actual = sample.input_coverage(nb_bases).unwrap();
+        let expected: u32 = input_read_lengths(Path::new("tests/cases/r1.fq.gz"))
+            .unwrap()
+            .iter()
+            .sum();
+
+        assert_eq!(nb_reads, 2);
+        assert_eq!(nb_bases, u64::from(expected));
+        assert_eq!(actual, f64::from(expected) / 4.0)
+    }
+
+    #[test]
+    fn report_includes_failures_and_exclusions() {
+        let results = vec![
+            SampleResult {
+                id: "s1".to_string(),
+                input_coverage: Some(5.0),
+                outcome: Outcome::Subsampled(ReadsSummary {
+                    reads: 2,
+                    bases: 20,
+                    coverage: Some(2.0),
+                    achievable: true,
+                }),
+            },
+            SampleResult {
+                id: "s2".to_string(),
+                input_coverage: None,
+                outcome: Outcome::Failed(anyhow!("bad\tthing")),
+            },
+            SampleResult {
+                id: "s3".to_string(),
+                input_coverage: Some(1.0),
+                outcome: Outcome::Excluded("too low".to_string()),
+            },
+        ];
+        let mut out: Vec<u8> = vec![];
+        write_report(&results, &mut out).unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected =
+            "sample\tstatus\tinput_coverage\treads\tbases\tcoverage\tachievable\treason\n\
+             s1\tok\t5.00\t2\t20\t2.00\ttrue\t\n\
+             s2\tfailed\t\t\t\t\t\tbad thing\n\
+             s3\texcluded\t1.00\t\t\t\t\ttoo low\n";
+
+        assert_eq!(actual, expected)
+    }
+}
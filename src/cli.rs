@@ -1,5 +1,7 @@
 use crate::alignment::Alignment;
+use crate::batch::Batch;
 use crate::reads::Reads;
+use crate::watch::Watch;
 use crate::Runner;
 use clap::{Parser, Subcommand};
 use regex::Regex;
@@ -34,7 +36,7 @@ pub struct Cli {
     pub verbose: bool,
 
     #[command(subcommand)]
-    pub(crate) command: Commands,
+    pub command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
@@ -44,6 +46,10 @@ pub enum Commands {
     /// Randomly subsample alignments to a specified depth of coverage
     #[command(name = "aln")]
     Alignment(Alignment),
+    /// Randomly subsample the reads of many samples listed in a sample sheet
+    Batch(Batch),
+    /// Watch a directory of fast{a,q} chunks and subsample once there is enough coverage
+    Watch(Watch),
     /// Get a bibtex formatted citation for this package.
     Cite(Cite),
 }
@@ -105,15 +111,8 @@ enum MetricSuffix {
 impl FromStr for MetricSuffix {
     type Err = CliError;
 
-    /// Parses a string into a `MetricSuffix`.
-    ///
-    /// # Example
-    /// ```rust
-    /// let s = "5.5mb";
-    /// let metric_suffix = MetricSuffix::from_str(s);
-    ///
-    /// assert_eq!(metric_suffix, MetricSuffix::Mega)
-    /// ```
+    /// Parses a string into a `MetricSuffix`. For instance, "mb" is parsed as
+    /// `MetricSuffix::Mega`.
     fn from_str(suffix: &str) -> Result<Self, Self::Err> {
         let suffix_lwr = suffix.to_lowercase();
         let metric_suffix = match suffix_lwr.as_str() {
@@ -130,16 +129,8 @@ impl FromStr for MetricSuffix {
     }
 }
 
-/// Allow for multiplying a `f64` by a `MetricSuffix`.
-///
-/// # Example
-///
-/// ```rust
-/// let metric_suffix = MetricSuffix::Mega;
-/// let x: f64 = 5.5;
-///
-/// assert_eq!(x * metric_suffix, 5_500_000)
-/// ```
+/// Allow for multiplying a `f64` by a `MetricSuffix` - e.g., 5.5 * `MetricSuffix::Mega` is
+/// 5,500,000.
 impl Mul<MetricSuffix> for f64 {
     type Output = Self;
 
@@ -164,7 +155,9 @@ pub struct GenomeSize(u64);
 /// # Example
 ///
 /// ```rust
-/// assert!(GenomeSize(10) == 10)
+/// use rasusa::GenomeSize;
+///
+/// assert!(GenomeSize::from(10) == 10)
 /// ```
 impl PartialEq<u64> for GenomeSize {
     fn eq(&self, other: &u64) -> bool {
@@ -172,6 +165,12 @@ impl PartialEq<u64> for GenomeSize {
     }
 }
 
+impl From<u64> for GenomeSize {
+    fn from(size: u64) -> Self {
+        GenomeSize(size)
+    }
+}
+
 impl From<GenomeSize> for u64 {
     fn from(g: GenomeSize) -> Self {
         g.0
@@ -192,10 +191,13 @@ impl FromStr for GenomeSize {
     ///
     /// # Example
     /// ```rust
+    /// use rasusa::GenomeSize;
+    /// use std::str::FromStr;
+    ///
     /// let s = "5.5mb";
-    /// let genome_size = GenomeSize::from_str(s);
+    /// let genome_size = GenomeSize::from_str(s).unwrap();
     ///
-    /// assert_eq!(genome_size, GenomeSize(5_500_000))
+    /// assert_eq!(genome_size, GenomeSize::from(5_500_000))
     /// ```
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let text = s.to_lowercase();
@@ -241,8 +243,10 @@ impl GenomeSize {
 /// # Example
 ///
 /// ```rust
-/// let genome_size = GenomeSize(100);
-/// let covg = Coverage(5);
+/// use rasusa::{Coverage, GenomeSize};
+///
+/// let genome_size = GenomeSize::from(100);
+/// let covg = Coverage(5.0);
 ///
 /// assert_eq!(genome_size * covg, 500)
 /// ```
@@ -259,8 +263,10 @@ impl Mul<Coverage> for GenomeSize {
 /// # Example
 ///
 /// ```rust
+/// use rasusa::GenomeSize;
+///
 /// let x: u64 = 210;
-/// let size = GenomeSize(200);
+/// let size = GenomeSize::from(200);
 ///
 /// let actual = x / size;
 /// let expected = 1.05;
@@ -285,7 +291,9 @@ pub struct Coverage(pub f32);
 /// # Example
 ///
 /// ```rust
-/// assert!(Coverage(10) == 10.0)
+/// use rasusa::Coverage;
+///
+/// assert!(Coverage(10.0) == 10.0)
 /// ```
 impl PartialEq<f32> for Coverage {
     fn eq(&self, other: &f32) -> bool {
@@ -300,10 +308,13 @@ impl FromStr for Coverage {
     ///
     /// # Example
     /// ```rust
+    /// use rasusa::Coverage;
+    /// use std::str::FromStr;
+    ///
     /// let s = "100x";
-    /// let covg = Coverage::from_str(s);
+    /// let covg = Coverage::from_str(s).unwrap();
     ///
-    /// assert_eq!(covg, Coverage(100))
+    /// assert_eq!(covg, Coverage(100.0))
     /// ```
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let re = Regex::new(r"^(?P<covg>[0-9]*\.?[0-9]+)(?i)x?$").unwrap();
@@ -329,8 +340,10 @@ impl FromStr for Coverage {
 /// # Example
 ///
 /// ```rust
-/// let covg = Coverage(5);
-/// let genome_size = GenomeSize(100);
+/// use rasusa::{Coverage, GenomeSize};
+///
+/// let covg = Coverage(5.0);
+/// let genome_size = GenomeSize::from(100);
 ///
 /// assert_eq!(covg * genome_size, 500)
 /// ```
@@ -381,6 +394,15 @@ pub(crate) fn check_path_exists<S: AsRef<OsStr> + ?Sized>(s: &S) -> Result<PathB
     }
 }
 
+/// Like [`check_path_exists`], but also accepts `-` for stdin
+pub(crate) fn check_path_exists_or_stdin(s: &str) -> Result<PathBuf, String> {
+    if s == "-" {
+        Ok(PathBuf::from(s))
+    } else {
+        check_path_exists(s)
+    }
+}
+
 /// A utility function to validate compression level is in allowed range
 #[allow(clippy::redundant_clone)]
 pub(crate) fn parse_level(s: &str) -> Result<niffler::Level, String> {
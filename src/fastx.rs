@@ -1,11 +1,52 @@
 use crate::cli::CompressionExt;
+use crate::shuffle::ExternalShuffle;
 use needletail::errors::ParseErrorKind::EmptyFile;
+use needletail::parser::{LineEnding, SequenceRecord};
 use niffler::compression;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+/// File extensions (ignoring any compression extension) that indicate fasta output.
+const FASTA_EXTENSIONS: &[&str] = &["fa", "fasta", "fna", "fas", "ffn", "faa"];
+const FASTQ_EXTENSIONS: &[&str] = &["fq", "fastq"];
+
+/// The header comment added to every read with `--tag`, followed by `=1` if the read was selected
+/// and `=0` if not.
+pub const KEEP_COMMENT: &str = "rasusa:keep";
+
+/// The ID of a read from its `header` - i.e., everything before the first whitespace.
+pub(crate) fn read_id(header: &[u8]) -> &[u8] {
+    header
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default()
+}
+
+/// The compression level used for `fmt` when no level is given.
+pub(crate) fn default_compression_level(fmt: compression::Format) -> compression::Level {
+    match fmt {
+        compression::Format::Gzip => compression::Level::Six,
+        compression::Format::Bzip => compression::Level::Nine,
+        compression::Format::Lzma => compression::Level::Six,
+        compression::Format::Zstd => compression::Level::Three,
+        _ => compression::Level::Zero,
+    }
+}
+
+/// The lowercase extension of `path`, after any compression extension - e.g., `fq` for
+/// `reads.FQ.gz`.
+fn sequence_extension(path: &Path) -> Option<String> {
+    let path = match niffler::Format::from_path(path) {
+        niffler::Format::No => path.to_path_buf(),
+        _ => path.with_extension(""),
+    };
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+}
+
 /// A collection of custom errors relating to the working with files for this package.
 #[derive(Error, Debug)]
 pub enum FastxError {
@@ -42,6 +83,113 @@ pub enum FastxError {
     WriteError { source: anyhow::Error },
 }
 
+/// Options controlling how records are written to the output.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutputFormat {
+    /// Write fasta records, regardless of whether the input is fastq.
+    pub fasta: bool,
+    /// Wrap fasta sequence lines at this many characters. Fastq records are never wrapped.
+    pub line_width: Option<usize>,
+    /// Remove everything after the first whitespace in read headers.
+    pub strip_comments: bool,
+}
+
+impl OutputFormat {
+    /// Whether records can be written back exactly as they were parsed.
+    pub fn is_passthrough(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether `path` has a fasta extension (ignoring any compression extension).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use rasusa::OutputFormat;
+    /// use std::path::Path;
+    ///
+    /// assert!(OutputFormat::is_fasta_path(Path::new("out.fa.gz")));
+    /// assert!(!OutputFormat::is_fasta_path(Path::new("out.fq")));
+    /// ```
+    pub fn is_fasta_path(path: &Path) -> bool {
+        sequence_extension(path).is_some_and(|ext| FASTA_EXTENSIONS.contains(&ext.as_str()))
+    }
+
+    /// Writes a single record to `writer` according to these options. If `qual` is `None`, or
+    /// fasta output was requested, a fasta record is written.
+    pub fn write_record(
+        &self,
+        id: &[u8],
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        line_ending: LineEnding,
+        writer: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        let ending = line_ending.to_bytes();
+        let id = if self.strip_comments {
+            id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id)
+        } else {
+            id
+        };
+
+        match qual {
+            Some(qual) if !self.fasta => {
+                writer.write_all(b"@")?;
+                writer.write_all(id)?;
+                writer.write_all(&ending)?;
+                writer.write_all(seq)?;
+                writer.write_all(&ending)?;
+                writer.write_all(b"+")?;
+                writer.write_all(&ending)?;
+                writer.write_all(qual)?;
+                writer.write_all(&ending)?;
+            }
+            _ => {
+                writer.write_all(b">")?;
+                writer.write_all(id)?;
+                writer.write_all(&ending)?;
+                match self.line_width {
+                    Some(width) if width > 0 => {
+                        for line in seq.chunks(width) {
+                            writer.write_all(line)?;
+                            writer.write_all(&ending)?;
+                        }
+                    }
+                    _ => {
+                        writer.write_all(seq)?;
+                        writer.write_all(&ending)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a single record in the same way as [`write_record`](#method.write_record), with
+    /// [`KEEP_COMMENT`] appended to the header to indicate whether the read was selected.
+    pub fn write_tagged_record(
+        &self,
+        id: &[u8],
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        line_ending: LineEnding,
+        keep: bool,
+        writer: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        let mut tagged_id = if self.strip_comments {
+            read_id(id).to_vec()
+        } else {
+            id.to_vec()
+        };
+        tagged_id.extend_from_slice(format!(" {}={}", KEEP_COMMENT, u8::from(keep)).as_bytes());
+        let format = OutputFormat {
+            strip_comments: false,
+            ..*self
+        };
+        format.write_record(&tagged_id, seq, qual, line_ending, writer)
+    }
+}
+
 /// A `Struct` used for seamlessly dealing with either compressed or uncompressed fasta/fastq files.
 #[derive(Debug, PartialEq)]
 pub struct Fastx {
@@ -55,6 +203,8 @@ impl Fastx {
     /// # Example
     ///
     /// ```rust
+    /// use rasusa::Fastx;
+    ///
     /// let path = std::path::Path::new("input.fa.gz");
     /// let fastx = Fastx::from_path(path);
     /// ```
@@ -63,6 +213,25 @@ impl Fastx {
             path: path.to_path_buf(),
         }
     }
+
+    /// Whether `path` has a fasta or fastq extension (ignoring any compression extension).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use rasusa::Fastx;
+    /// use std::path::Path;
+    ///
+    /// assert!(Fastx::is_fastx_path(Path::new("chunk_0.fastq.gz")));
+    /// assert!(Fastx::is_fastx_path(Path::new("chunk_0.FA")));
+    /// assert!(!Fastx::is_fastx_path(Path::new("sequencing_summary.txt")));
+    /// ```
+    pub fn is_fastx_path(path: &Path) -> bool {
+        sequence_extension(path).is_some_and(|ext| {
+            FASTA_EXTENSIONS.contains(&ext.as_str()) || FASTQ_EXTENSIONS.contains(&ext.as_str())
+        })
+    }
+
     /// Create the file associated with this `Fastx` object for writing.
     ///
     /// # Errors
@@ -72,12 +241,16 @@ impl Fastx {
     /// # Example
     ///
     /// ```rust
-    /// let path = std::path::Path::new("output.fa");
-    /// let fastx = Fastx{ path };
+    /// use rasusa::Fastx;
+    /// use std::io::Write;
+    ///
+    /// let output = tempfile::Builder::new().suffix(".fa").tempfile()?;
+    /// let fastx = Fastx::from_path(output.path());
     /// { // this scoping means the file handle is closed afterwards.
-    ///     let file_handle = fastx.create(6, None)?;
+    ///     let mut file_handle = fastx.create(Some(niffler::Level::Six), None)?;
     ///     write!(file_handle, ">read1\nACGT\n")?
     /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
     /// ```
     pub fn create(
         &self,
@@ -87,13 +260,7 @@ impl Fastx {
         let file = File::create(&self.path).map_err(|source| FastxError::CreateError { source })?;
         let file_handle = Box::new(BufWriter::new(file));
         let fmt = compression_fmt.unwrap_or_else(|| niffler::Format::from_path(&self.path));
-        let compression_lvl = compression_lvl.unwrap_or(match fmt {
-            compression::Format::Gzip => compression::Level::Six,
-            compression::Format::Bzip => compression::Level::Nine,
-            compression::Format::Lzma => compression::Level::Six,
-            compression::Format::Zstd => compression::Level::Three,
-            _ => compression::Level::Zero,
-        });
+        let compression_lvl = compression_lvl.unwrap_or_else(|| default_compression_level(fmt));
         niffler::get_writer(file_handle, fmt, compression_lvl)
             .map_err(FastxError::CompressOutputError)
     }
@@ -107,10 +274,13 @@ impl Fastx {
     /// # Example
     ///
     /// ```rust
+    /// use rasusa::Fastx;
+    /// use std::io::Write;
+    ///
     /// let text = "@read1\nACGT\n+\n!!!!\n@read2\nG\n+\n!";
     /// let mut file = tempfile::Builder::new().suffix(".fq").tempfile().unwrap();
     /// file.write_all(text.as_bytes()).unwrap();
-    /// let fastx = Fastx{ file.path() };
+    /// let fastx = Fastx::from_path(file.path());
     /// let actual = fastx.read_lengths().unwrap();
     /// let expected: Vec<u32> = vec![4, 1];
     /// assert_eq!(actual, expected)
@@ -139,8 +309,38 @@ impl Fastx {
         Ok(read_lengths)
     }
 
+    /// Returns a vector containing the IDs of all the reads in the file. The ID is the header up
+    /// to the first whitespace, i.e., without any comment.
+    ///
+    /// # Errors
+    /// If the file cannot be opened or there is an issue parsing any records then an
+    /// `Err` containing a variant of [`FastxError`](#fastxerror) is returned.
+    pub fn read_ids(&self) -> Result<Vec<Vec<u8>>, FastxError> {
+        let mut read_ids: Vec<Vec<u8>> = vec![];
+        let reader = match niffler::send::from_path(&self.path) {
+            Ok((rdr, _)) => rdr,
+            Err(source) => match source {
+                niffler::error::Error::FileTooShort => return Ok(read_ids),
+                _ => return Err(FastxError::CompressOutputError(source)),
+            },
+        };
+        let mut reader = match needletail::parse_fastx_reader(reader) {
+            Ok(rdr) => rdr,
+            Err(e) if e.kind == EmptyFile => return Ok(read_ids),
+            Err(source) => return Err(FastxError::ReadError { source }),
+        };
+
+        while let Some(record) = reader.next() {
+            match record {
+                Ok(rec) => read_ids.push(read_id(rec.id()).to_vec()),
+                Err(err) => return Err(FastxError::ParseError { source: err }),
+            }
+        }
+        Ok(read_ids)
+    }
+
     /// Writes reads, with indices contained within `reads_to_keep`, to the specified handle
-    /// `write_to`.
+    /// `write_to`. Records are converted according to `output_format`.
     ///
     /// # Errors
     /// This function could raise an `Err` instance of [`FastxError`](#fastxerror) in the following
@@ -148,22 +348,26 @@ impl Fastx {
     /// -   If the file (of `self`) cannot be opened.
     /// -   If writing to `write_to` fails.
     /// -   If, after iterating through all reads in the file, there is still elements left in
-    /// `reads_to_keep`. *Note: in this case, this function still writes all reads where indices
-    /// were found in the file.*
+    ///     `reads_to_keep`. *Note: in this case, this function still writes all reads where indices
+    ///     were found in the file.*
     ///
     /// # Example
     ///
     /// ```rust
+    /// use rasusa::{Fastx, OutputFormat};
+    /// use std::io::Write;
+    ///
     /// let text = "@read1\nACGT\n+\n!!!!\n@read2\nCCCC\n+\n$$$$\n";
     /// let mut input = tempfile::Builder::new().suffix(".fastq").tempfile().unwrap();
     /// input.write_all(text.as_bytes()).unwrap();
-    /// let fastx = Fastx::from_path(input.path()).unwrap();
-    /// let mut reads_to_keep: Vec<bool> = vec![false, true]);
-    /// let output = Builder::new().suffix(".fastq").tempfile().unwrap();
-    /// let output_fastx = Fastx::from_path(output.path()).unwrap();
+    /// let fastx = Fastx::from_path(input.path());
+    /// let reads_to_keep: Vec<bool> = vec![false, true];
+    /// let output = tempfile::Builder::new().suffix(".fastq").tempfile().unwrap();
+    /// let output_fastx = Fastx::from_path(output.path());
     /// {
-    ///     let mut out_fh = output_fastx.create().unwrap();
-    ///     let filter_result = fastx.filter_reads_into(&mut reads_to_keep, 1, &mut out_fh);
+    ///     let mut out_fh = output_fastx.create(None, None).unwrap();
+    ///     let filter_result =
+    ///         fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh, &OutputFormat::default());
     ///     assert!(filter_result.is_ok());
     /// }
     /// let actual = std::fs::read_to_string(output).unwrap();
@@ -175,6 +379,7 @@ impl Fastx {
         reads_to_keep: &[bool],
         nb_reads_keep: usize,
         write_to: &mut T,
+        output_format: &OutputFormat,
     ) -> Result<usize, FastxError> {
         let mut total_len = 0;
         let (reader, _) = niffler::send::from_path(&self.path)?;
@@ -188,10 +393,7 @@ impl Fastx {
                 Err(source) => return Err(FastxError::ParseError { source }),
                 Ok(rec) if reads_to_keep[read_idx] => {
                     total_len += rec.num_bases();
-                    rec.write(write_to, None)
-                        .map_err(|err| FastxError::WriteError {
-                            source: anyhow::Error::from(err),
-                        })?;
+                    write_fastx_record(&rec, write_to, output_format)?;
                     nb_reads_written += 1;
                     if nb_reads_keep == nb_reads_written {
                         break;
@@ -211,6 +413,210 @@ impl Fastx {
     }
 }
 
+impl Fastx {
+    /// Writes reads, with indices contained within `reads_to_keep`, to `write_to` and all other
+    /// reads to `unselected_to`, in a single pass over the file. Returns the number of bases
+    /// written to `write_to`.
+    ///
+    /// # Errors
+    /// This function returns an `Err` in the same circumstances as
+    /// [`filter_reads_into`](#method.filter_reads_into).
+    pub fn split_reads_into<T: Write, U: Write>(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        write_to: &mut T,
+        unselected_to: &mut U,
+        output_format: &OutputFormat,
+        unselected_format: &OutputFormat,
+    ) -> Result<usize, FastxError> {
+        let mut total_len = 0;
+        let (reader, _) = niffler::send::from_path(&self.path)?;
+        let mut reader = match needletail::parse_fastx_reader(reader) {
+            Ok(rdr) => rdr,
+            Err(e) if e.kind == EmptyFile && nb_reads_keep == 0 => return Ok(total_len),
+            Err(source) => return Err(FastxError::ReadError { source }),
+        };
+        let mut read_idx: usize = 0;
+        let mut nb_reads_written = 0;
+
+        while let Some(record) = reader.next() {
+            let rec = record.map_err(|source| FastxError::ParseError { source })?;
+            if reads_to_keep.get(read_idx).copied().unwrap_or(false) {
+                total_len += rec.num_bases();
+                write_fastx_record(&rec, write_to, output_format)?;
+                nb_reads_written += 1;
+            } else {
+                write_fastx_record(&rec, unselected_to, unselected_format)?;
+            }
+            read_idx += 1;
+        }
+
+        if nb_reads_written == nb_reads_keep {
+            Ok(total_len)
+        } else {
+            Err(FastxError::IndicesNotFound)
+        }
+    }
+
+    /// Writes every read to `write_to`, with a [`KEEP_COMMENT`] header comment indicating whether
+    /// its index is contained within `reads_to_keep`. Returns the number of bases in the selected
+    /// reads.
+    ///
+    /// # Errors
+    /// This function returns an `Err` in the same circumstances as
+    /// [`filter_reads_into`](#method.filter_reads_into).
+    pub fn tag_reads_into<T: Write>(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        write_to: &mut T,
+        output_format: &OutputFormat,
+    ) -> Result<usize, FastxError> {
+        let mut total_len = 0;
+        let (reader, _) = niffler::send::from_path(&self.path)?;
+        let mut reader = match needletail::parse_fastx_reader(reader) {
+            Ok(rdr) => rdr,
+            Err(e) if e.kind == EmptyFile && nb_reads_keep == 0 => return Ok(total_len),
+            Err(source) => return Err(FastxError::ReadError { source }),
+        };
+        let mut read_idx: usize = 0;
+        let mut nb_reads_kept = 0;
+
+        while let Some(record) = reader.next() {
+            let rec = record.map_err(|source| FastxError::ParseError { source })?;
+            let keep = reads_to_keep.get(read_idx).copied().unwrap_or(false);
+            if keep {
+                total_len += rec.num_bases();
+                nb_reads_kept += 1;
+            }
+            output_format
+                .write_tagged_record(
+                    rec.id(),
+                    &rec.seq(),
+                    rec.qual(),
+                    rec.line_ending(),
+                    keep,
+                    write_to,
+                )
+                .map_err(|err| FastxError::WriteError {
+                    source: anyhow::Error::from(err),
+                })?;
+            read_idx += 1;
+        }
+
+        if nb_reads_kept == nb_reads_keep {
+            Ok(total_len)
+        } else {
+            Err(FastxError::IndicesNotFound)
+        }
+    }
+
+    /// Writes reads, with indices contained within `order`, to the specified handle `write_to` in
+    /// the order they appear in `order`, rather than the order of the file. At most `chunk_size`
+    /// reads are held in memory at once; the rest are buffered in temporary files.
+    ///
+    /// # Errors
+    /// This function returns an `Err` in the same circumstances as
+    /// [`filter_reads_into`](#method.filter_reads_into), or if the temporary files cannot be
+    /// created or read.
+    pub fn filter_reads_into_shuffled<T: Write>(
+        &self,
+        order: &[u32],
+        chunk_size: usize,
+        write_to: &mut T,
+        output_format: &OutputFormat,
+    ) -> Result<usize, FastxError> {
+        self.split_reads_into_shuffled(order, chunk_size, write_to, output_format, None)
+    }
+
+    /// The same as [`filter_reads_into_shuffled`](#method.filter_reads_into_shuffled), but the
+    /// reads that were not selected are written, in the order of the file, to `unselected` (along
+    /// with the format to write them in) if given.
+    pub fn split_reads_into_shuffled<T: Write>(
+        &self,
+        order: &[u32],
+        chunk_size: usize,
+        write_to: &mut T,
+        output_format: &OutputFormat,
+        mut unselected: Option<(&mut dyn Write, &OutputFormat)>,
+    ) -> Result<usize, FastxError> {
+        let mut ranks: Vec<u32> = vec![u32::MAX; order.iter().max().map_or(0, |&i| i as usize + 1)];
+        for (rank, &idx) in order.iter().enumerate() {
+            ranks[idx as usize] = rank as u32;
+        }
+
+        let write_err = |err: std::io::Error| FastxError::WriteError {
+            source: anyhow::Error::from(err),
+        };
+        let mut shuffler = ExternalShuffle::new(order.len(), chunk_size).map_err(write_err)?;
+        let mut total_len = 0;
+        let mut nb_reads_seen = 0;
+
+        if !order.is_empty() || unselected.is_some() {
+            let (reader, _) = niffler::send::from_path(&self.path)?;
+            let mut reader = match needletail::parse_fastx_reader(reader) {
+                Ok(rdr) => rdr,
+                Err(e) if e.kind == EmptyFile && order.is_empty() => return Ok(total_len),
+                Err(source) => return Err(FastxError::ReadError { source }),
+            };
+            let mut read_idx: usize = 0;
+
+            while let Some(record) = reader.next() {
+                let rec = record.map_err(|source| FastxError::ParseError { source })?;
+                match ranks.get(read_idx) {
+                    Some(&rank) if rank != u32::MAX => {
+                        total_len += rec.num_bases();
+                        shuffler
+                            .push(rank, rec.id(), &rec.seq(), rec.qual())
+                            .map_err(write_err)?;
+                        nb_reads_seen += 1;
+                    }
+                    _ => match unselected.as_mut() {
+                        Some((unselected_to, unselected_format)) => {
+                            write_fastx_record(&rec, &mut **unselected_to, unselected_format)?
+                        }
+                        None if read_idx >= ranks.len() => break,
+                        None => (),
+                    },
+                }
+                read_idx += 1;
+            }
+        }
+
+        if nb_reads_seen != order.len() {
+            return Err(FastxError::IndicesNotFound);
+        }
+        shuffler
+            .write_into(write_to, output_format)
+            .map_err(write_err)?;
+        Ok(total_len)
+    }
+}
+
+/// Writes `rec` to `write_to` according to `output_format`. Records are written exactly as they
+/// were parsed if no conversion is needed.
+fn write_fastx_record(
+    rec: &SequenceRecord,
+    write_to: &mut dyn Write,
+    output_format: &OutputFormat,
+) -> Result<(), FastxError> {
+    let result = if output_format.is_passthrough() {
+        rec.write(write_to, None).map_err(anyhow::Error::from)
+    } else {
+        output_format
+            .write_record(
+                rec.id(),
+                &rec.seq(),
+                rec.qual(),
+                rec.line_ending(),
+                write_to,
+            )
+            .map_err(anyhow::Error::from)
+    };
+    result.map_err(|source| FastxError::WriteError { source })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -240,10 +646,7 @@ mod tests {
             .err()
             .unwrap();
         let expected = FastxError::CreateError {
-            source: std::io::Error::new(
-                std::io::ErrorKind::Other,
-                String::from("No such file or directory (os error 2)"),
-            ),
+            source: std::io::Error::other(String::from("No such file or directory (os error 2)")),
         };
 
         assert_eq!(actual.type_id(), expected.type_id())
@@ -312,6 +715,19 @@ mod tests {
         assert_eq!(actual, expected)
     }
 
+    #[test]
+    fn get_read_ids_strips_comments() {
+        let text = "@read1 runid=abc\nACGT\n+\n!!!!\n@read2\tch=1\nG\n+\n!";
+        let mut file = Builder::new().suffix(".fq").tempfile().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(file.path());
+
+        let actual = fastx.read_ids().unwrap();
+        let expected: Vec<Vec<u8>> = vec![b"read1".to_vec(), b"read2".to_vec()];
+
+        assert_eq!(actual, expected)
+    }
+
     #[test]
     fn filter_reads_empty_indices_no_output() {
         let text = "@read1\nACGT\n+\n!!!!";
@@ -322,7 +738,8 @@ mod tests {
         let output = Builder::new().suffix(".fastq").tempfile().unwrap();
         let output_fastx = Fastx::from_path(output.path());
         let mut out_fh = output_fastx.create(None, None).unwrap();
-        let filter_result = fastx.filter_reads_into(&reads_to_keep, 0, &mut out_fh);
+        let filter_result =
+            fastx.filter_reads_into(&reads_to_keep, 0, &mut out_fh, &OutputFormat::default());
 
         assert!(filter_result.is_ok());
 
@@ -344,7 +761,8 @@ mod tests {
         let output_fastx = Fastx::from_path(output.path());
         {
             let mut out_fh = output_fastx.create(None, None).unwrap();
-            let filter_result = fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh);
+            let filter_result =
+                fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh, &OutputFormat::default());
             assert!(filter_result.is_ok());
         }
 
@@ -365,7 +783,8 @@ mod tests {
         let output_fastx = Fastx::from_path(output.path());
         {
             let mut out_fh = output_fastx.create(None, None).unwrap();
-            let filter_result = fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh);
+            let filter_result =
+                fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh, &OutputFormat::default());
             assert!(filter_result.is_ok());
         }
 
@@ -386,7 +805,8 @@ mod tests {
         let output_fastx = Fastx::from_path(output.path());
         {
             let mut out_fh = output_fastx.create(None, None).unwrap();
-            let filter_result = fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh);
+            let filter_result =
+                fastx.filter_reads_into(&reads_to_keep, 1, &mut out_fh, &OutputFormat::default());
             assert!(filter_result.is_ok());
         }
 
@@ -407,7 +827,8 @@ mod tests {
         let output_fastx = Fastx::from_path(output.path());
         {
             let mut out_fh = output_fastx.create(None, None).unwrap();
-            let filter_result = fastx.filter_reads_into(&reads_to_keep, 2, &mut out_fh);
+            let filter_result =
+                fastx.filter_reads_into(&reads_to_keep, 2, &mut out_fh, &OutputFormat::default());
             assert!(filter_result.is_ok());
         }
 
@@ -430,7 +851,8 @@ mod tests {
             let mut out_fh = output_fastx
                 .create(Some(niffler::Level::Four), None)
                 .unwrap();
-            let filter_result = fastx.filter_reads_into(&reads_to_keep, 2, &mut out_fh);
+            let filter_result =
+                fastx.filter_reads_into(&reads_to_keep, 2, &mut out_fh, &OutputFormat::default());
             assert!(filter_result.is_err());
         }
 
@@ -453,7 +875,8 @@ mod tests {
             let mut out_fh = output_fastx
                 .create(Some(niffler::Level::Four), None)
                 .unwrap();
-            let filter_result = fastx.filter_reads_into(&reads_to_keep, 2, &mut out_fh);
+            let filter_result =
+                fastx.filter_reads_into(&reads_to_keep, 2, &mut out_fh, &OutputFormat::default());
             assert!(filter_result.is_err());
         }
 
@@ -462,4 +885,263 @@ mod tests {
 
         assert_eq!(actual, expected)
     }
+
+    #[test]
+    fn fasta_path_detected_through_compression_extension() {
+        assert!(OutputFormat::is_fasta_path(Path::new("out.fa")));
+        assert!(OutputFormat::is_fasta_path(Path::new("out.FASTA.gz")));
+        assert!(OutputFormat::is_fasta_path(Path::new("out.fna.bz2")));
+        assert!(!OutputFormat::is_fasta_path(Path::new("out.fq.gz")));
+        assert!(!OutputFormat::is_fasta_path(Path::new("out.gz")));
+        assert!(!OutputFormat::is_fasta_path(Path::new("out")));
+    }
+
+    #[test]
+    fn fastx_path_detected_for_every_fasta_and_fastq_extension() {
+        for ext in FASTA_EXTENSIONS.iter().chain(FASTQ_EXTENSIONS) {
+            assert!(Fastx::is_fastx_path(Path::new(&format!("chunk.{}", ext))));
+            assert!(Fastx::is_fastx_path(Path::new(&format!(
+                "chunk.{}.gz",
+                ext
+            ))));
+        }
+        assert!(Fastx::is_fastx_path(Path::new("chunk_0.FQ")));
+        assert!(!Fastx::is_fastx_path(Path::new("sequencing_summary.txt")));
+        assert!(!Fastx::is_fastx_path(Path::new("chunk_0.bam")));
+        assert!(!Fastx::is_fastx_path(Path::new("chunk_0.gz")));
+    }
+
+    #[test]
+    fn write_record_fastq_as_wrapped_fasta_without_comment() {
+        let output_format = OutputFormat {
+            fasta: true,
+            line_width: Some(3),
+            strip_comments: true,
+        };
+        let mut out: Vec<u8> = vec![];
+
+        output_format
+            .write_record(
+                b"read1 length=7",
+                b"ACGTACG",
+                Some(b"!!!!!!!"),
+                LineEnding::Unix,
+                &mut out,
+            )
+            .unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = ">read1\nACG\nTAC\nG\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn write_record_fastq_is_never_wrapped() {
+        let output_format = OutputFormat {
+            line_width: Some(2),
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = vec![];
+
+        output_format
+            .write_record(b"read1", b"ACGT", Some(b"!!!!"), LineEnding::Unix, &mut out)
+            .unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = "@read1\nACGT\n+\n!!!!\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn filter_fastq_reads_into_fasta() {
+        let text = "@read1 comment\nACGT\n+\n!!!!\n@read2\nCCCC\n+\n$$$$\n";
+        let mut input = Builder::new().suffix(".fastq").tempfile().unwrap();
+        input.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(input.path());
+        let reads_to_keep: Vec<bool> = vec![true, false];
+        let output_format = OutputFormat {
+            fasta: true,
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = vec![];
+
+        let filter_result = fastx.filter_reads_into(&reads_to_keep, 1, &mut out, &output_format);
+        assert!(filter_result.is_ok());
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = ">read1 comment\nACGT\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn filter_reads_into_shuffled_uses_given_order() {
+        let text = "@read1\nACGT\n+\n!!!!\n@read2\nCC\n+\n$$\n@read3\nA\n+\n$\n";
+        let mut input = Builder::new().suffix(".fastq").tempfile().unwrap();
+        input.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(input.path());
+        let order: Vec<u32> = vec![2, 0];
+        let mut out: Vec<u8> = vec![];
+
+        let total_len = fastx
+            .filter_reads_into_shuffled(&order, 1, &mut out, &OutputFormat::default())
+            .unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = "@read3\nA\n+\n$\n@read1\nACGT\n+\n!!!!\n";
+
+        assert_eq!(total_len, 5);
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn filter_reads_into_shuffled_index_out_of_range() {
+        let text = ">read1\nACGT\n";
+        let mut input = Builder::new().suffix(".fa").tempfile().unwrap();
+        input.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(input.path());
+        let order: Vec<u32> = vec![1, 0];
+        let mut out: Vec<u8> = vec![];
+
+        let actual =
+            fastx.filter_reads_into_shuffled(&order, 10, &mut out, &OutputFormat::default());
+
+        assert!(actual.is_err())
+    }
+
+    #[test]
+    fn split_reads_into_writes_unselected_reads() {
+        let text = "@read1\nACGT\n+\n!!!!\n@read2\nCC\n+\n$$\n@read3\nA\n+\n$\n";
+        let mut input = Builder::new().suffix(".fastq").tempfile().unwrap();
+        input.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(input.path());
+        let reads_to_keep = vec![false, true, false];
+        let mut out: Vec<u8> = vec![];
+        let mut unselected: Vec<u8> = vec![];
+        let unselected_format = OutputFormat {
+            fasta: true,
+            ..Default::default()
+        };
+
+        let total_len = fastx
+            .split_reads_into(
+                &reads_to_keep,
+                1,
+                &mut out,
+                &mut unselected,
+                &OutputFormat::default(),
+                &unselected_format,
+            )
+            .unwrap();
+
+        assert_eq!(total_len, 2);
+        assert_eq!(String::from_utf8(out).unwrap(), "@read2\nCC\n+\n$$\n");
+        assert_eq!(
+            String::from_utf8(unselected).unwrap(),
+            ">read1\nACGT\n>read3\nA\n"
+        )
+    }
+
+    #[test]
+    fn split_reads_into_shuffled_writes_unselected_in_file_order() {
+        let text = "@read1\nACGT\n+\n!!!!\n@read2\nCC\n+\n$$\n@read3\nA\n+\n$\n@read4\nG\n+\n$\n";
+        let mut input = Builder::new().suffix(".fastq").tempfile().unwrap();
+        input.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(input.path());
+        let order: Vec<u32> = vec![1, 0];
+        let mut out: Vec<u8> = vec![];
+        let mut unselected: Vec<u8> = vec![];
+        let unselected_format = OutputFormat::default();
+
+        fastx
+            .split_reads_into_shuffled(
+                &order,
+                10,
+                &mut out,
+                &OutputFormat::default(),
+                Some((&mut unselected, &unselected_format)),
+            )
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@read2\nCC\n+\n$$\n@read1\nACGT\n+\n!!!!\n"
+        );
+        assert_eq!(
+            String::from_utf8(unselected).unwrap(),
+            "@read3\nA\n+\n$\n@read4\nG\n+\n$\n"
+        )
+    }
+
+    #[test]
+    fn split_reads_into_shuffled_with_empty_input_writes_nothing() {
+        let input = Builder::new().suffix(".fastq.gz").tempfile().unwrap();
+        let fastx = Fastx::from_path(input.path());
+        // a compressed file with no reads in it
+        drop(fastx.create(None, None).unwrap());
+        let mut out: Vec<u8> = vec![];
+        let mut unselected: Vec<u8> = vec![];
+        let unselected_format = OutputFormat::default();
+
+        let total_len = fastx
+            .split_reads_into_shuffled(
+                &[],
+                10,
+                &mut out,
+                &OutputFormat::default(),
+                Some((&mut unselected, &unselected_format)),
+            )
+            .unwrap();
+
+        assert_eq!(total_len, 0);
+        assert!(out.is_empty());
+        assert!(unselected.is_empty())
+    }
+
+    #[test]
+    fn tag_reads_into_writes_all_reads_with_comment() {
+        let text = "@read1 runid=1\nACGT\n+\n!!!!\n@read2\nCC\n+\n$$\n";
+        let mut input = Builder::new().suffix(".fastq").tempfile().unwrap();
+        input.write_all(text.as_bytes()).unwrap();
+        let fastx = Fastx::from_path(input.path());
+        let mut out: Vec<u8> = vec![];
+
+        let total_len = fastx
+            .tag_reads_into(&[false, true], 1, &mut out, &OutputFormat::default())
+            .unwrap();
+
+        assert_eq!(total_len, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@read1 runid=1 rasusa:keep=0\nACGT\n+\n!!!!\n@read2 rasusa:keep=1\nCC\n+\n$$\n"
+        )
+    }
+
+    #[test]
+    fn write_tagged_record_strips_other_comments() {
+        let format = OutputFormat {
+            fasta: true,
+            strip_comments: true,
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = vec![];
+
+        format
+            .write_tagged_record(
+                b"read1 runid=1",
+                b"ACGT",
+                None,
+                LineEnding::Unix,
+                true,
+                &mut out,
+            )
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ">read1 rasusa:keep=1\nACGT\n"
+        )
+    }
 }
@@ -0,0 +1,67 @@
+//! Randomly subsample reads or alignments.
+//!
+//! This crate provides the subsampling engine behind the `rasusa` command line tool, so it can be
+//! used from other Rust programs without shelling out.
+//!
+//! # Example
+//!
+//! Subsample a fastq file to 30x coverage of a 4.4Mb genome
+//!
+//! ```no_run
+//! use rasusa::{Coverage, Fastx, GenomeSize, OutputFormat, SubSampler};
+//! use std::path::Path;
+//!
+//! let genome_size: GenomeSize = "4.4mb".parse()?;
+//! let coverage: Coverage = "30x".parse()?;
+//! let input = Fastx::from_path(Path::new("reads.fq.gz"));
+//!
+//! let read_lengths = input.read_lengths()?;
+//! let sampler = SubSampler::new(Some(genome_size * coverage), None, Some(42))?;
+//! let (reads_to_keep, nb_reads_to_keep) = sampler.indices(&read_lengths);
+//!
+//! let mut output = Fastx::from_path(Path::new("subsampled.fq.gz")).create(None, None)?;
+//! input.filter_reads_into(&reads_to_keep, nb_reads_to_keep, &mut output, &OutputFormat::default())?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Custom selection methods can be plugged in by implementing [`SamplingStrategy`] and passing it
+//! to [`SubSampler::from_strategy`].
+#![allow(clippy::redundant_clone)]
+
+extern crate core;
+
+use anyhow::Result;
+
+pub use crate::alignment::{Alignment, KEEP_AUX_TAG};
+pub use crate::batch::{Batch, BatchError, CohortTarget, Outcome, Sample, SampleResult};
+pub use crate::cli::{Cli, CliError, Commands, Coverage, GenomeSize};
+pub use crate::fastx::{Fastx, FastxError, OutputFormat, KEEP_COMMENT};
+pub use crate::reads::{Reads, ReadsSummary};
+pub use crate::region::{Region, RegionDepth, RegionError};
+pub use crate::strategy::{
+    NameHash, ReadMetadata, SamplingStrategy, Target, TopUp, UniformBases, UniformCount,
+};
+pub use crate::subsampler::{SamplingError, SubSampler};
+pub use crate::ubam::Ubam;
+pub use crate::watch::{DirectoryTracker, Watch};
+
+mod alignment;
+mod batch;
+mod cli;
+mod fastx;
+#[cfg(feature = "python")]
+mod python;
+mod reads;
+mod region;
+mod shuffle;
+mod sort;
+mod strategy;
+mod subsampler;
+mod ubam;
+mod utils;
+mod watch;
+
+/// A (sub)command that can be run.
+pub trait Runner {
+    fn run(&mut self) -> Result<()>;
+}
@@ -1,26 +1,9 @@
-#![allow(clippy::redundant_clone)]
-
-extern crate core;
-
 use anyhow::Result;
 use clap::Parser;
 use env_logger::Builder;
 use log::{debug, LevelFilter};
 
-pub use crate::cli::Cli;
-use crate::cli::Commands;
-pub use crate::fastx::Fastx;
-pub use crate::subsampler::SubSampler;
-
-mod alignment;
-mod cli;
-mod fastx;
-mod reads;
-mod subsampler;
-
-pub trait Runner {
-    fn run(&mut self) -> Result<()>;
-}
+use rasusa::{Cli, Commands, Runner};
 
 fn main() -> Result<()> {
     let args: Cli = Cli::parse();
@@ -42,6 +25,8 @@ fn main() -> Result<()> {
     let mut subcmd: Box<dyn Runner> = match args.command {
         Commands::Reads(cmd) => Box::new(cmd),
         Commands::Alignment(cmd) => Box::new(cmd),
+        Commands::Batch(cmd) => Box::new(cmd),
+        Commands::Watch(cmd) => Box::new(cmd),
         Commands::Cite(cmd) => Box::new(cmd),
     };
 
@@ -0,0 +1,238 @@
+//! Python bindings, enabled with the `python` feature.
+//!
+//! The functions here go through the same code paths as the command line, so the same seed gives
+//! the same subsample.
+use std::path::PathBuf;
+
+use clap::Parser;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::{Alignment, Reads, Runner};
+
+fn runtime_error(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", e))
+}
+
+/// Randomly select reads until a target number of bases or reads is reached.
+#[pyclass(name = "SubSampler", module = "rasusa")]
+struct PySubSampler {
+    inner: crate::SubSampler,
+}
+
+#[pymethods]
+impl PySubSampler {
+    /// Exactly one of `bases` and `num_reads` must be given.
+    #[new]
+    #[pyo3(signature = (*, bases=None, num_reads=None, seed=None))]
+    fn new(bases: Option<u64>, num_reads: Option<u64>, seed: Option<u64>) -> PyResult<Self> {
+        let inner = crate::SubSampler::new(bases, num_reads, seed)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(PySubSampler { inner })
+    }
+
+    /// Returns a list, the same length as `lengths`, indicating which reads were selected.
+    fn indices(&self, lengths: Vec<u32>) -> Vec<bool> {
+        self.inner.indices(&lengths).0
+    }
+
+    /// Returns the indices of the selected reads in the order they were selected.
+    fn ordered_indices(&self, lengths: Vec<u32>) -> Vec<u32> {
+        self.inner.ordered_indices(&lengths)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.inner)
+    }
+}
+
+/// Subsample read files in the same way as `rasusa reads`.
+///
+/// `genome_size`, `coverage`, `bases` and `num` accept anything the command line does (e.g.
+/// `"4.4mb"` or `30`).
+#[pyfunction]
+#[pyo3(signature = (
+    inputs,
+    outputs,
+    *,
+    genome_size=None,
+    coverage=None,
+    bases=None,
+    num=None,
+    frac=None,
+    seed=None,
+    output_type=None,
+    compress_level=None,
+    fasta=false,
+    shuffle_output=false,
+))]
+#[allow(clippy::too_many_arguments)]
+fn subsample_reads(
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+    genome_size: Option<&Bound<'_, PyAny>>,
+    coverage: Option<&Bound<'_, PyAny>>,
+    bases: Option<&Bound<'_, PyAny>>,
+    num: Option<&Bound<'_, PyAny>>,
+    frac: Option<f32>,
+    seed: Option<u64>,
+    output_type: Option<String>,
+    compress_level: Option<u8>,
+    fasta: bool,
+    shuffle_output: bool,
+) -> PyResult<()> {
+    let mut args: Vec<String> = vec!["reads".to_string()];
+    args.extend(inputs.iter().map(|p| p.to_string_lossy().into_owned()));
+    for output in &outputs {
+        args.push("-o".to_string());
+        args.push(output.to_string_lossy().into_owned());
+    }
+    for (flag, value) in [
+        ("--genome-size", genome_size),
+        ("--coverage", coverage),
+        ("--bases", bases),
+        ("--num", num),
+    ] {
+        if let Some(value) = value {
+            args.push(flag.to_string());
+            args.push(value.str()?.to_string());
+        }
+    }
+    if let Some(frac) = frac {
+        args.push("--frac".to_string());
+        args.push(frac.to_string());
+    }
+    if let Some(seed) = seed {
+        args.push("--seed".to_string());
+        args.push(seed.to_string());
+    }
+    if let Some(output_type) = output_type {
+        args.push("--output-type".to_string());
+        args.push(output_type);
+    }
+    if let Some(level) = compress_level {
+        args.push("--compress-level".to_string());
+        args.push(level.to_string());
+    }
+    if fasta {
+        args.push("--fasta".to_string());
+    }
+    if shuffle_output {
+        args.push("--shuffle-output".to_string());
+    }
+
+    let mut reads =
+        Reads::try_parse_from(args).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    reads.run().map_err(runtime_error)
+}
+
+/// Subsample an indexed alignment file in the same way as `rasusa aln`.
+#[pyfunction]
+#[pyo3(signature = (aln, output, coverage, *, seed=None, output_type=None))]
+fn subsample_alignment(
+    aln: PathBuf,
+    output: PathBuf,
+    coverage: u32,
+    seed: Option<u64>,
+    output_type: Option<&str>,
+) -> PyResult<()> {
+    if coverage < 1 {
+        return Err(PyValueError::new_err("coverage must be at least 1"));
+    }
+    if !aln.exists() {
+        return Err(PyValueError::new_err(format!("{:?} does not exist", aln)));
+    }
+
+    let mut alignment = Alignment::new(aln, coverage);
+    alignment.output = Some(output);
+    alignment.seed = seed;
+    alignment.output_type = output_type
+        .map(crate::alignment::infer_format_from_char)
+        .transpose()
+        .map_err(PyValueError::new_err)?;
+    alignment.run().map_err(runtime_error)
+}
+
+#[pymodule]
+fn rasusa(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_class::<PySubSampler>()?;
+    m.add_function(wrap_pyfunction!(subsample_reads, m)?)?;
+    m.add_function(wrap_pyfunction!(subsample_alignment, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_cmd::Command;
+    use std::path::Path;
+
+    fn rasusa(args: &[&str], output: &Path) {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(args)
+            .arg("-o")
+            .arg(output)
+            .assert()
+            .success();
+    }
+
+    #[test]
+    fn subsample_reads_matches_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let infile = "tests/cases/file1.fq.gz";
+        let py_output = dir.path().join("python.fq");
+        let cli_output = dir.path().join("cli.fq");
+
+        subsample_reads(
+            vec![PathBuf::from(infile)],
+            vec![py_output.clone()],
+            None,
+            None,
+            None,
+            None,
+            Some(0.5),
+            Some(42),
+            None,
+            None,
+            false,
+            false,
+        )
+        .unwrap();
+        rasusa(&["reads", "--frac", "0.5", "-s", "42", infile], &cli_output);
+
+        let expected = std::fs::read(cli_output).unwrap();
+        assert!(!expected.is_empty());
+        assert_eq!(std::fs::read(py_output).unwrap(), expected);
+    }
+
+    #[test]
+    fn subsample_alignment_matches_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let infile = "tests/cases/no_start_end.bam";
+        let py_output = dir.path().join("python.sam");
+        let cli_output = dir.path().join("cli.sam");
+
+        subsample_alignment(PathBuf::from(infile), py_output.clone(), 5, Some(42), None).unwrap();
+        rasusa(&["aln", "-c", "5", "-s", "42", infile], &cli_output);
+
+        let expected = std::fs::read(cli_output).unwrap();
+        assert!(!expected.is_empty());
+        assert_eq!(std::fs::read(py_output).unwrap(), expected);
+    }
+
+    #[test]
+    fn subsampler_indices_match_ordered_indices() {
+        let lengths = vec![10, 20, 30, 40, 50];
+        let sampler = PySubSampler::new(None, Some(3), Some(7)).unwrap();
+
+        let mask = sampler.indices(lengths.clone());
+        let mut selected: Vec<u32> = sampler.ordered_indices(lengths);
+        selected.sort_unstable();
+        let expected: Vec<u32> = (0..5).filter(|&i| mask[i as usize]).collect();
+
+        assert_eq!(selected, expected);
+        assert_eq!(mask.iter().filter(|&&keep| keep).count(), 3);
+    }
+}
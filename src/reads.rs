@@ -1,24 +1,32 @@
+use crate::alignment::infer_format_from_path;
 use crate::cli::{
     check_path_exists, parse_compression_format, parse_fraction, parse_level, CliError, Coverage,
     GenomeSize,
 };
-use crate::{Fastx, Runner, SubSampler};
-use anyhow::{Context, Result};
+use crate::fastx::{default_compression_level, read_id, OutputFormat};
+use crate::strategy::{selection_mask, Target};
+use crate::ubam::Ubam;
+use crate::{Fastx, Runner, SamplingError, SubSampler};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use log::{debug, error, info, warn};
-use niffler::compression;
-use std::io::stdout;
-use std::path::PathBuf;
+use log::{debug, info, warn};
+use rust_htslib::bam;
+use std::collections::HashSet;
+use std::io::{stdout, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
 pub struct Reads {
     /// The fast{a,q} file(s) to subsample.
     ///
-    /// For paired Illumina, the order matters. i.e., R1 then R2.
+    /// Unaligned SAM/BAM/CRAM files (e.g., from ONT or PacBio basecallers) are also accepted.
+    ///
+    /// For paired Illumina, the order matters. i.e., R1 then R2. More than two files can be given
+    /// for linked reads (e.g., I1, R1, R2) and they will all be subsampled in lockstep.
     #[arg(
     value_parser = check_path_exists,
-    num_args = 1..=2,
+    num_args = 1..,
     required = true,
     name = "FILE(S)"
     )]
@@ -28,12 +36,31 @@ pub struct Reads {
     ///
     /// For paired Illumina pass this flag twice `-o o1.fq -o o2.fq`  
     ///
+    /// For unaligned SAM/BAM/CRAM input, the output is written in the format of the output file
+    /// extension, with all tags preserved. Any other extension will output fastq.  
+    ///
     /// NOTE: The order of the pairs is assumed to be the same as the input - e.g., R1 then R2.  
     ///
     /// This option is required for paired input.
     #[arg(short = 'o', long = "output", action = clap::ArgAction::Append)]
     pub output: Vec<PathBuf>,
 
+    /// Output filepath(s) for the reads that were not selected
+    ///
+    /// Gives a clean split of the input - every read is written to either --output or
+    /// --unselected-output. For paired input, pass this flag twice in the same order as the input.
+    /// Compression and format are handled the same way as --output
+    #[arg(long, value_name = "FILE", action = clap::ArgAction::Append)]
+    pub unselected_output: Vec<PathBuf>,
+
+    /// Input file(s) whose read lengths count towards the target number of bases.
+    ///
+    /// Files are referred to by their 1-based position in the input - e.g., `--target-files 2`
+    /// for I1, R1, R2 input will only use R1 to calculate coverage. Can be passed multiple times
+    /// or as a comma-separated list. By default, all input files are used.
+    #[arg(long, value_name = "INT", value_delimiter = ',', value_parser = clap::value_parser!(u16).range(1..))]
+    pub target_files: Vec<u16>,
+
     /// Genome size to calculate coverage with respect to. e.g., 4.3kb, 7Tb, 9000, 4.1MB
     ///
     /// Alternatively, a FASTA/Q index file can be provided and the genome size will be
@@ -94,7 +121,8 @@ pub struct Reads {
     ///
     /// Rasusa will attempt to infer the output compression format automatically from the filename
     /// extension. This option is used to override that. If writing to stdout, the default is
-    /// uncompressed
+    /// uncompressed. For unaligned SAM/BAM/CRAM input written to stdout, passing this option
+    /// outputs fastq rather than the input format
     #[clap(short = 'O', long, value_name = "u|b|g|l|x|z", value_parser = parse_compression_format)]
     pub output_type: Option<niffler::compression::Format>,
 
@@ -102,6 +130,67 @@ pub struct Reads {
     /// not specified.
     #[clap(short = 'l', long, value_parser = parse_level, value_name = "1-21")]
     pub compress_level: Option<niffler::Level>,
+
+    /// Write the output as fasta, even if the input is fastq
+    ///
+    /// Fasta output is also used if the output filename has a fasta extension - e.g., out.fa.gz
+    #[clap(long)]
+    pub fasta: bool,
+
+    /// Wrap fasta sequence lines at this many characters. Fastq output is never wrapped
+    #[clap(long, value_name = "INT")]
+    pub line_width: Option<usize>,
+
+    /// Remove header comments (everything after the first whitespace) from the read names
+    #[clap(long)]
+    pub strip_comments: bool,
+
+    /// Write the selected reads in a random order, rather than the order of the input
+    ///
+    /// By default, the output preserves the input order - e.g., ONT reads stay ordered by
+    /// acquisition time. Not supported for SAM/BAM/CRAM input.
+    #[clap(long)]
+    pub shuffle_output: bool,
+
+    /// Maximum number of reads to hold in memory when using --shuffle-output
+    ///
+    /// If more reads are selected, they are buffered in temporary files. This option accepts the
+    /// same format as genome size - e.g., 1m is one million reads
+    #[clap(long, value_name = "INT", default_value = "1m", value_parser = parse_read_count, requires = "shuffle_output")]
+    pub shuffle_chunk_size: usize,
+
+    /// Top up a previous subsample: keep all reads selected by a previous run and add randomly
+    /// selected reads until the new target is reached
+    ///
+    /// The previous selection can be the output of the previous run (fast{a,q} or SAM/BAM/CRAM)
+    /// or a file with one read ID per line. Previous reads are matched to the first input by ID
+    #[clap(long, value_name = "FILE", value_parser = check_path_exists)]
+    pub top_up: Option<PathBuf>,
+
+    /// Select reads by a seeded hash of their name, rather than by their position in the input
+    ///
+    /// The same reads are then selected from re-sorted, re-chunked or re-compressed copies of the
+    /// input. With --frac, each read is kept or dropped on its own, so R1 and R2 files (with or
+    /// without /1 and /2 suffixes) subsampled separately also match. With other targets, reads are
+    /// selected in hash order until the target is reached. A seed of 0 is used if --seed is not
+    /// given
+    #[clap(long, conflicts_with = "top_up")]
+    pub hash: bool,
+
+    /// Report how many reads and bases would be kept, without writing any output
+    ///
+    /// The read lengths are still gathered and the reads selected, so the report is exactly what
+    /// a run with the same seed would keep. The report is written to stdout
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Write every read, annotated with whether it was selected, rather than only the selected
+    /// reads
+    ///
+    /// A `rasusa:keep=1` (selected) or `rasusa:keep=0` comment is added to each read header. For
+    /// SAM/BAM/CRAM output, an `rk:i:1` or `rk:i:0` tag is added to each record instead
+    #[clap(long, conflicts_with_all = &["unselected_output", "shuffle_output", "dry_run"])]
+    pub tag: bool,
 }
 
 impl Reads {
@@ -109,22 +198,55 @@ impl Reads {
     ///
     /// # Errors
     /// A [`CliError::BadInputOutputCombination`](#clierror) is returned for the following:
-    /// - Either `--input` or `--output` are passed more than twice
     /// - An unequal number of `--input` and `--output` are passed. The only exception to
-    /// this is if one `--input` and zero `--output` are passed, in which case, the output
-    /// will be sent to STDOUT.
+    ///   this is if one `--input` and zero `--output` are passed, in which case, the output
+    ///   will be sent to STDOUT.
+    /// - A `--target-files` index is greater than the number of inputs.
+    /// - `--shuffle-output` is used with SAM/BAM/CRAM input.
+    /// - `--unselected-output` is given, but not once for every input.
+    /// - An `--unselected-output` is not the same kind of file (SAM/BAM/CRAM or fast{a,q}) as
+    ///   its `--output`.
     pub fn validate_input_output_combination(&self) -> std::result::Result<(), CliError> {
         let out_len = self.output.len();
         let in_len = self.input.len();
 
-        if in_len > 2 {
-            let msg = String::from("Got more than 2 files for input.");
-            return Err(CliError::BadInputOutputCombination(msg));
+        if let Some(idx) = self.target_files.iter().find(|&&i| usize::from(i) > in_len) {
+            return Err(CliError::BadInputOutputCombination(format!(
+                "--target-files {} is out of range for {} --input",
+                idx, in_len
+            )));
+        }
+
+        if self.shuffle_output && self.input.iter().any(|p| Ubam::is_ubam_path(p)) {
+            return Err(CliError::BadInputOutputCombination(String::from(
+                "--shuffle-output is not supported for SAM/BAM/CRAM input",
+            )));
         }
-        if out_len > 2 {
-            let msg = String::from("Got more than 2 files for output.");
-            return Err(CliError::BadInputOutputCombination(msg));
+
+        let unselected_len = self.unselected_output.len();
+        if unselected_len > 0 && unselected_len != in_len {
+            return Err(CliError::BadInputOutputCombination(format!(
+                "Got {} --input but {} --unselected-output",
+                in_len, unselected_len
+            )));
+        }
+        // checked before any output is created, so an invalid combination leaves no files behind
+        for (idx, path) in self.unselected_output.iter().enumerate() {
+            let main_is_aln = match self.output.get(idx) {
+                Some(main) => infer_format_from_path(main).is_some(),
+                None => {
+                    self.output_type.is_none()
+                        && !self.fasta
+                        && infer_format_from_path(&self.input[idx]).is_some()
+                }
+            };
+            if main_is_aln != infer_format_from_path(path).is_some() {
+                return Err(CliError::BadInputOutputCombination(String::from(
+                    "--unselected-output must be the same kind of file (SAM/BAM/CRAM or fast{a,q}) as --output",
+                )));
+            }
         }
+
         match in_len as isize - out_len as isize {
             diff if diff == 1 && in_len == 1 => Ok(()),
             diff if diff != 0 => Err(CliError::BadInputOutputCombination(format!(
@@ -134,38 +256,333 @@ impl Reads {
             _ => Ok(()),
         }
     }
+
+    /// Creates the output for the input file at (0-based) `idx`. Unaligned SAM/BAM/CRAM input is
+    /// written in the same format unless the output path (or `--output-type` when writing to
+    /// stdout) asks for fastq.
+    fn create_output(&self, idx: usize) -> Result<ReadsOutput> {
+        self.create_output_at(idx, self.output.get(idx))
+            .context(format!("unable to create output file {}", idx + 1))
+    }
+
+    /// Creates the `--unselected-output` for the input file at (0-based) `idx`, if given. It is
+    /// the same kind of output (SAM/BAM/CRAM or fast{a,q}) as the main output, as checked by
+    /// [`validate_input_output_combination`](#method.validate_input_output_combination).
+    fn create_unselected_output(&self, idx: usize) -> Result<Option<ReadsOutput>> {
+        let Some(path) = self.unselected_output.get(idx) else {
+            return Ok(None);
+        };
+        let output = self.create_output_at(idx, Some(path)).context(format!(
+            "unable to create unselected output file {}",
+            idx + 1
+        ))?;
+        Ok(Some(output))
+    }
+
+    fn create_output_at(&self, idx: usize, output: Option<&PathBuf>) -> Result<ReadsOutput> {
+        let input = &self.input[idx];
+        let aln_fmt = match output {
+            Some(path) => infer_format_from_path(path),
+            None if self.output_type.is_none() && !self.fasta => infer_format_from_path(input),
+            None => None,
+        };
+
+        if let Some(fmt) = aln_fmt {
+            if !Ubam::is_ubam_path(input) {
+                return Err(CliError::BadInputOutputCombination(String::from(
+                    "SAM/BAM/CRAM output is only possible with SAM/BAM/CRAM input",
+                ))
+                .into());
+            }
+            let writer = Ubam::from_path(input).create(output.map(|p| p.as_path()), fmt)?;
+            return Ok(ReadsOutput::Alignment(writer));
+        }
+
+        let handle = match output {
+            None => match self.output_type {
+                None => Box::new(stdout()),
+                Some(fmt) => niffler::basic::get_writer(
+                    Box::new(stdout()),
+                    fmt,
+                    default_compression_level(fmt),
+                )?,
+            },
+            Some(path) => Fastx::from_path(path).create(self.compress_level, self.output_type)?,
+        };
+        Ok(ReadsOutput::Fastx(handle))
+    }
+
+    /// The format to write reads to `output` in.
+    fn output_format(&self, output: Option<&PathBuf>) -> OutputFormat {
+        let fasta_path = output.is_some_and(|p| OutputFormat::is_fasta_path(p));
+        OutputFormat {
+            fasta: self.fasta || fasta_path,
+            line_width: self.line_width,
+            strip_comments: self.strip_comments,
+        }
+    }
+
+    /// Writes the selected reads of the input file at (0-based) `idx` to `output`, and the others
+    /// to `unselected` if given, and returns the number of bases selected. With `--tag`, all reads
+    /// are written to `output`, annotated with whether they were selected. `order` holds the
+    /// indices of the selected reads in the order they were selected and `reads_to_keep` the same
+    /// selection as a mask.
+    fn filter_input_into(
+        &self,
+        idx: usize,
+        order: &[u32],
+        reads_to_keep: &[bool],
+        output: &mut ReadsOutput,
+        unselected: Option<&mut ReadsOutput>,
+    ) -> Result<usize> {
+        let path = &self.input[idx];
+        let nb_reads_keep = order.len();
+        let output_format = self.output_format(self.output.get(idx));
+        let unselected_format = self.output_format(self.unselected_output.get(idx));
+        if self.tag {
+            return match output {
+                ReadsOutput::Alignment(writer) => {
+                    Ubam::from_path(path).tag_reads_into(reads_to_keep, nb_reads_keep, writer)
+                }
+                ReadsOutput::Fastx(handle) if Ubam::is_ubam_path(path) => Ubam::from_path(path)
+                    .tag_reads_into_fastq(reads_to_keep, nb_reads_keep, handle, &output_format),
+                ReadsOutput::Fastx(handle) => Ok(Fastx::from_path(path).tag_reads_into(
+                    reads_to_keep,
+                    nb_reads_keep,
+                    handle,
+                    &output_format,
+                )?),
+            };
+        }
+        match (output, unselected) {
+            (ReadsOutput::Alignment(writer), unselected) => {
+                let unselected = match unselected {
+                    Some(ReadsOutput::Alignment(w)) => Some(w),
+                    _ => None,
+                };
+                Ubam::from_path(path).filter_reads_into(
+                    reads_to_keep,
+                    nb_reads_keep,
+                    writer,
+                    unselected,
+                )
+            }
+            (ReadsOutput::Fastx(handle), unselected) => {
+                let unselected = match unselected {
+                    Some(ReadsOutput::Fastx(h)) => {
+                        Some((h.as_mut() as &mut dyn Write, &unselected_format))
+                    }
+                    _ => None,
+                };
+                if Ubam::is_ubam_path(path) {
+                    Ubam::from_path(path).filter_reads_into_fastq(
+                        reads_to_keep,
+                        nb_reads_keep,
+                        handle,
+                        &output_format,
+                        unselected,
+                    )
+                } else if self.shuffle_output {
+                    Ok(Fastx::from_path(path).split_reads_into_shuffled(
+                        order,
+                        self.shuffle_chunk_size,
+                        handle,
+                        &output_format,
+                        unselected,
+                    )?)
+                } else if let Some((mut unselected_to, unselected_format)) = unselected {
+                    Ok(Fastx::from_path(path).split_reads_into(
+                        reads_to_keep,
+                        nb_reads_keep,
+                        handle,
+                        &mut unselected_to,
+                        &output_format,
+                        unselected_format,
+                    )?)
+                } else {
+                    Ok(Fastx::from_path(path).filter_reads_into(
+                        reads_to_keep,
+                        nb_reads_keep,
+                        handle,
+                        &output_format,
+                    )?)
+                }
+            }
+        }
+    }
+
+    /// Whether the reads in the input file at (0-based) `idx` count towards the target number of
+    /// bases.
+    fn is_target_file(&self, idx: usize) -> bool {
+        self.target_files.is_empty() || self.target_files.iter().any(|&i| usize::from(i) == idx + 1)
+    }
+}
+
+/// Parses a number of reads in the same format as genome size - e.g., 1k is 1000 reads.
+fn parse_read_count(s: &str) -> std::result::Result<usize, CliError> {
+    Ok(u64::from(s.parse::<GenomeSize>()?) as usize)
+}
+
+/// Returns the IDs of all reads in `path`, which can be fast{a,q} or unaligned SAM/BAM/CRAM.
+fn input_read_ids(path: &Path) -> Result<Vec<Vec<u8>>> {
+    if Ubam::is_ubam_path(path) {
+        Ubam::from_path(path).read_ids()
+    } else {
+        Ok(Fastx::from_path(path).read_ids()?)
+    }
+}
+
+/// Returns the IDs of the reads selected by a previous run from `path`. This is either the output
+/// of that run, or a file with one ID per line - any leading @ or > and anything after the first
+/// whitespace are ignored.
+fn previous_read_ids(path: &Path) -> Result<HashSet<Vec<u8>>> {
+    if Ubam::is_ubam_path(path) || Fastx::is_fastx_path(path) {
+        return Ok(input_read_ids(path)?.into_iter().collect());
+    }
+    let (reader, _) =
+        niffler::from_path(path).context(format!("Failed to open read ID file {:?}", path))?;
+    let mut ids = HashSet::new();
+    for line in BufReader::new(reader).split(b'\n') {
+        let line = line.context("Failed to read read ID file")?;
+        let start = line
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(line.len());
+        let line = &line[start..];
+        let line = line
+            .strip_prefix(b"@")
+            .or_else(|| line.strip_prefix(b">"))
+            .unwrap_or(line);
+        let id = read_id(line);
+        if !id.is_empty() {
+            ids.insert(id.to_vec());
+        }
+    }
+    Ok(ids)
+}
+
+/// What was (or, for a `--dry-run`, would have been) kept by [`Reads::subsample`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadsSummary {
+    /// The number of reads kept from each input.
+    pub reads: usize,
+    /// The number of bases kept from the `--target-files`.
+    pub bases: u64,
+    /// The coverage of the kept bases. Only known when a genome size is given.
+    pub coverage: Option<f64>,
+    /// Whether the requested number of bases or reads could be reached.
+    pub achievable: bool,
+}
+
+impl ReadsSummary {
+    /// Writes the report as tab-separated key-value lines.
+    fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "reads\t{}", self.reads)?;
+        writeln!(w, "bases\t{}", self.bases)?;
+        if let Some(covg) = self.coverage {
+            writeln!(w, "coverage\t{:.2}", covg)?;
+        }
+        writeln!(w, "achievable\t{}", self.achievable)
+    }
+}
+
+/// Where the subsampled reads for a single input file are written to.
+enum ReadsOutput {
+    Fastx(Box<dyn Write>),
+    Alignment(bam::Writer),
+}
+
+/// Returns the lengths of all reads in `path`, which can be fast{a,q} or unaligned SAM/BAM/CRAM.
+pub(crate) fn input_read_lengths(path: &Path) -> Result<Vec<u32>> {
+    if Ubam::is_ubam_path(path) {
+        Ubam::from_path(path).read_lengths()
+    } else {
+        Ok(Fastx::from_path(path).read_lengths()?)
+    }
+}
+
+/// Returns the lengths of the reads in `inputs`, which are linked (e.g., paired) so must have the
+/// same number of reads. The length of a read is the sum of its lengths in the inputs for which
+/// `is_target` is true.
+pub(crate) fn combined_read_lengths(
+    inputs: &[PathBuf],
+    is_target: impl Fn(usize) -> bool,
+) -> Result<Vec<u32>> {
+    info!("Gathering read lengths...");
+    let first_lengths = input_read_lengths(&inputs[0])
+        .context("unable to gather read lengths for the first input file")?;
+    let expected_num_reads = first_lengths.len();
+    let mut read_lengths = if is_target(0) {
+        first_lengths
+    } else {
+        vec![0; expected_num_reads]
+    };
+
+    for (i, path) in inputs.iter().enumerate().skip(1) {
+        info!("Gathering read lengths for input file {}...", i + 1);
+        let other_lengths = input_read_lengths(path).context(format!(
+            "unable to gather read lengths for input file {}",
+            i + 1
+        ))?;
+
+        if other_lengths.len() != expected_num_reads {
+            return Err(anyhow!("First input has {} reads, but input {} has {} reads. Paired or linked input files are assumed to have the same number of reads, in the same order.", expected_num_reads, i + 1, other_lengths.len()));
+        }
+        if is_target(i) {
+            // add the linked read lengths to the existing lengths
+            for (j, len) in other_lengths.iter().enumerate() {
+                read_lengths[j] += len;
+            }
+        }
+    }
+    if inputs.len() > 1 {
+        info!(
+            "All input files have the same number of reads ({}) 👍",
+            expected_num_reads
+        );
+    }
+    Ok(read_lengths)
 }
 
 impl Runner for Reads {
     fn run(&mut self) -> Result<()> {
+        let summary = self.subsample()?;
+
+        if self.dry_run {
+            summary
+                .write_to(&mut stdout())
+                .context("unable to write dry run report")?;
+        }
+
+        info!("Done 🎉");
+        Ok(())
+    }
+}
+
+impl Reads {
+    /// Subsamples the inputs and returns a summary of what was kept. If `--dry-run` was given,
+    /// the reads are selected but nothing is written.
+    pub fn subsample(&self) -> Result<ReadsSummary> {
         self.validate_input_output_combination()?;
-        let is_paired = self.input.len() == 2;
+        let num_inputs = self.input.len();
+        let is_paired = num_inputs == 2;
         if is_paired {
             info!("Two input files given. Assuming paired Illumina...")
+        } else if num_inputs > 2 {
+            info!(
+                "{} input files given. Assuming linked reads that will be subsampled in lockstep...",
+                num_inputs
+            )
         }
 
-        let input_fastx = Fastx::from_path(&self.input[0]);
-
-        let mut output_handle = match self.output.len() {
-            0 => match self.output_type {
-                None => Box::new(stdout()),
-                Some(fmt) => {
-                    let lvl = match fmt {
-                        compression::Format::Gzip => compression::Level::Six,
-                        compression::Format::Bzip => compression::Level::Nine,
-                        compression::Format::Lzma => compression::Level::Six,
-                        compression::Format::Zstd => compression::Level::Three,
-                        _ => compression::Level::Zero,
-                    };
-                    niffler::basic::get_writer(Box::new(stdout()), fmt, lvl)?
-                }
-            },
-            _ => {
-                let out_fastx = Fastx::from_path(&self.output[0]);
-                out_fastx
-                    .create(self.compress_level, self.output_type)
-                    .context("unable to create the first output file")?
-            }
+        // create all outputs up front so we fail early if any can't be created
+        let mut outputs = if self.dry_run {
+            info!("Dry run - no output will be written");
+            vec![]
+        } else {
+            (0..num_inputs)
+                .map(|i| Ok((self.create_output(i)?, self.create_unselected_output(i)?)))
+                .collect::<Result<Vec<_>>>()?
         };
 
         let target_total_bases: Option<u64> = match (self.genome_size, self.coverage, self.bases) {
@@ -174,46 +591,17 @@ impl Runner for Reads {
             _ => None,
         };
 
-        if target_total_bases.is_some() {
-            info!(
-                "Target number of bases to subsample to is: {}",
-                target_total_bases.unwrap()
-            );
+        if let Some(ttb) = target_total_bases {
+            info!("Target number of bases to subsample to is: {}", ttb);
         }
 
-        info!("Gathering read lengths...");
-        let mut read_lengths = input_fastx
-            .read_lengths()
-            .context("unable to gather read lengths for the first input file")?;
-
-        if is_paired {
-            let second_input_fastx = Fastx::from_path(&self.input[1]);
-            let expected_num_reads = read_lengths.len();
-            info!("Gathering read lengths for second input file...");
-            let mate_lengths = second_input_fastx
-                .read_lengths()
-                .context("unable to gather read lengths for the second input file")?;
-
-            if mate_lengths.len() != expected_num_reads {
-                error!("First input has {} reads, but the second has {} reads. Paired Illumina files are assumed to have the same number of reads. The results of this subsample may not be as expected now.", expected_num_reads, read_lengths.len());
-                std::process::exit(1);
-            } else {
-                info!(
-                    "Both input files have the same number of reads ({}) 👍",
-                    expected_num_reads
-                );
-            }
-            // add the paired read lengths to the existing lengths
-            for (i, len) in mate_lengths.iter().enumerate() {
-                read_lengths[i] += len;
-            }
-        }
+        let read_lengths = combined_read_lengths(&self.input, |i| self.is_target_file(i))?;
         info!("{} reads detected", read_lengths.len());
 
         // calculate the depth of coverage if using coverage-based subsampling
-        if self.genome_size.is_some() {
+        if let Some(gsize) = self.genome_size {
             let number_of_bases: u64 = read_lengths.iter().map(|&x| x as u64).sum();
-            let depth_of_covg = (number_of_bases as f64) / f64::from(self.genome_size.unwrap());
+            let depth_of_covg = (number_of_bases as f64) / f64::from(gsize);
             info!("Input coverage is {:.2}x", depth_of_covg);
         }
 
@@ -233,37 +621,70 @@ impl Runner for Reads {
             _ => None,
         };
 
-        let subsampler = SubSampler {
-            target_total_bases,
-            seed: self.seed,
-            num_reads,
+        let order = match &self.top_up {
+            None if self.hash => {
+                let read_ids = input_read_ids(&self.input[0])
+                    .context("unable to gather read IDs for the first input file")?;
+                let target = match (target_total_bases, num_reads, self.frac) {
+                    (_, _, Some(frac)) => Target::Fraction(f64::from(frac)),
+                    (Some(bases), None, _) => Target::Bases(bases),
+                    (None, Some(n), _) => Target::Reads(n),
+                    _ => return Err(SamplingError::InvalidTargetCombination.into()),
+                };
+                SubSampler::name_hash(target, self.seed.unwrap_or(0), read_ids)
+                    .ordered_indices(&read_lengths)
+            }
+            None => SubSampler::new(target_total_bases, num_reads, self.seed)?
+                .ordered_indices(&read_lengths),
+            Some(path) => {
+                let previous = previous_read_ids(path)
+                    .context("unable to gather the previously selected read IDs")?;
+                let read_ids = input_read_ids(&self.input[0])
+                    .context("unable to gather read IDs for the first input file")?;
+                let nb_missing = {
+                    let input_ids: HashSet<&[u8]> =
+                        read_ids.iter().map(|id| id.as_slice()).collect();
+                    previous
+                        .iter()
+                        .filter(|id| !input_ids.contains(id.as_slice()))
+                        .count()
+                };
+                if nb_missing > 0 {
+                    return Err(anyhow!(
+                        "{} of the {} previously selected reads are not in the first input file",
+                        nb_missing,
+                        previous.len()
+                    ));
+                }
+                info!("Topping up {} previously selected reads", previous.len());
+                SubSampler::top_up(previous, read_ids, target_total_bases, num_reads, self.seed)?
+                    .ordered_indices(&read_lengths)
+            }
         };
-
-        let (reads_to_keep, nb_reads_to_keep) = subsampler.indices(&read_lengths);
-        if is_paired {
+        let nb_reads_to_keep = order.len();
+        let reads_to_keep = selection_mask(&order, read_lengths.len());
+        if num_inputs > 1 {
             info!("Keeping {} reads from each input", nb_reads_to_keep);
         } else {
             info!("Keeping {} reads", nb_reads_to_keep);
         }
         debug!("Indices of reads being kept:\n{:?}", reads_to_keep);
 
-        let mut total_kept_bases =
-            input_fastx.filter_reads_into(&reads_to_keep, nb_reads_to_keep, &mut output_handle)?
-                as u64;
-
-        // repeat the same process for the second input fastx (if illumina)
-        if is_paired {
-            let second_input_fastx = Fastx::from_path(&self.input[1]);
-            let second_out_fastx = Fastx::from_path(&self.output[1]);
-            let mut second_output_handle = second_out_fastx
-                .create(self.compress_level, self.output_type)
-                .context("unable to create the second output file")?;
-
-            total_kept_bases += second_input_fastx.filter_reads_into(
-                &reads_to_keep,
-                nb_reads_to_keep,
-                &mut second_output_handle,
-            )? as u64;
+        let mut total_kept_bases: u64 = if self.dry_run {
+            order
+                .iter()
+                .map(|&i| u64::from(read_lengths[i as usize]))
+                .sum()
+        } else {
+            0
+        };
+        for (i, (output, unselected)) in outputs.iter_mut().enumerate() {
+            let kept_bases = self
+                .filter_input_into(i, &order, &reads_to_keep, output, unselected.as_mut())
+                .context(format!("unable to subsample input file {}", i + 1))?;
+            if self.is_target_file(i) {
+                total_kept_bases += kept_bases as u64;
+            }
         }
 
         if let Some(gsize) = self.genome_size {
@@ -283,8 +704,18 @@ impl Runner for Reads {
             info!("Kept {} bases", total_kept_bases);
         }
 
-        info!("Done 🎉");
-        Ok(())
+        Ok(ReadsSummary {
+            reads: nb_reads_to_keep,
+            bases: total_kept_bases,
+            coverage: self
+                .genome_size
+                .map(|gsize| total_kept_bases as f64 / f64::from(gsize)),
+            achievable: match (target_total_bases, num_reads) {
+                (Some(ttb), _) => total_kept_bases >= ttb,
+                (_, Some(n)) => nb_reads_to_keep as u64 >= n,
+                _ => true,
+            },
+        })
     }
 }
 
@@ -496,7 +927,7 @@ mod tests {
     }
 
     #[test]
-    fn three_inputs_raises_error() {
+    fn three_inputs_one_output_raises_error() {
         let infile = "tests/cases/r1.fq.gz";
         let passed_args = vec![
             SUB,
@@ -551,6 +982,86 @@ mod tests {
         cmd.args(passed_args).assert().success();
     }
 
+    #[test]
+    fn three_inputs_three_outputs_is_ok() {
+        let infile = "tests/cases/r1.fq.gz";
+        let passed_args = vec![
+            SUB, infile, infile, infile, "-n", "1", "-s", "88", "-o", "out.fq", "-o", "out.fq",
+            "-o", "out.fq",
+        ];
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(passed_args).assert().success();
+    }
+
+    #[test]
+    fn unselected_output_for_only_one_of_two_inputs_raises_error() {
+        let infile = "tests/cases/r1.fq.gz";
+        let passed_args = vec![
+            SUB,
+            infile,
+            infile,
+            "-n",
+            "1",
+            "-o",
+            "out.fq",
+            "-o",
+            "out.fq",
+            "--unselected-output",
+            "out.fq",
+        ];
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(passed_args)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "2 --input but 1 --unselected-output",
+            ));
+    }
+
+    #[test]
+    fn target_files_out_of_range_raises_error() {
+        let infile = "tests/cases/r1.fq.gz";
+        let passed_args = vec![
+            SUB,
+            infile,
+            infile,
+            "-b",
+            "5",
+            "--target-files",
+            "3",
+            "-o",
+            "out.fq",
+            "-o",
+            "out.fq",
+        ];
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(passed_args).assert().failure();
+    }
+
+    #[test]
+    fn target_files_zero_raises_error() {
+        let infile = "tests/cases/r1.fq.gz";
+        let passed_args = vec![
+            SUB,
+            infile,
+            infile,
+            "-b",
+            "5",
+            "--target-files",
+            "0",
+            "-o",
+            "out.fq",
+            "-o",
+            "out.fq",
+        ];
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(passed_args).assert().failure();
+    }
+
     #[test]
     fn two_inputs_one_output_raises_error() {
         let infile = "tests/cases/r1.fq.gz";
@@ -594,4 +1105,66 @@ mod tests {
         let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
         cmd.args(passed_args).assert().success();
     }
+
+    #[test]
+    fn dry_run_does_not_create_output() {
+        let infile = "tests/cases/r1.fq.gz";
+        let outfile = tempfile::Builder::new()
+            .suffix(".fq")
+            .tempfile()
+            .unwrap()
+            .into_temp_path();
+        let outpath = outfile.to_path_buf();
+        outfile.close().unwrap();
+        let passed_args = vec![
+            SUB,
+            infile,
+            "-n",
+            "2",
+            "-s",
+            "88",
+            "-o",
+            outpath.to_str().unwrap(),
+            "--dry-run",
+        ];
+
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        cmd.args(passed_args)
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("reads\t2\n"))
+            .stdout(predicates::str::contains("achievable\ttrue\n"));
+
+        assert!(!outpath.exists())
+    }
+
+    #[test]
+    fn summary_report_with_coverage() {
+        let report = super::ReadsSummary {
+            reads: 3,
+            bases: 250,
+            coverage: Some(2.5),
+            achievable: false,
+        };
+        let mut out: Vec<u8> = vec![];
+        report.write_to(&mut out).unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = "reads\t3\nbases\t250\ncoverage\t2.50\nachievable\tfalse\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn previous_read_ids_from_id_list() {
+        let mut file = tempfile::Builder::new().suffix(".txt").tempfile().unwrap();
+        std::io::Write::write_all(&mut file, b"@read1 comment\n\n>read2\n  read3\tx\nread1\n")
+            .unwrap();
+
+        let actual = super::previous_read_ids(file.path()).unwrap();
+        let expected: std::collections::HashSet<Vec<u8>> =
+            [b"read1".to_vec(), b"read2".to_vec(), b"read3".to_vec()].into();
+
+        assert_eq!(actual, expected)
+    }
 }
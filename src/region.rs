@@ -0,0 +1,398 @@
+//! Regions of the genome to restrict alignment subsampling to.
+use std::fmt;
+use std::io::BufRead;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// A collection of custom errors relating to parsing regions.
+#[derive(Error, Debug)]
+pub enum RegionError {
+    /// Indicates a string cannot be parsed into a [`Region`](#region).
+    #[error("{0} is not a valid region. Use chrom or chrom:start-end, with a 1-based, inclusive start and end")]
+    InvalidRegion(String),
+
+    /// Indicates a line of a BED file could not be parsed.
+    #[error("Line {line} of the BED file: {msg}")]
+    InvalidBedLine { line: usize, msg: String },
+
+    /// Indicates the BED file could not be read.
+    #[error("Failed to read BED file")]
+    ReadError(#[from] std::io::Error),
+}
+
+/// A 0-based, half-open interval on a chromosome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// The name of the chromosome.
+    pub chrom: String,
+    /// The 0-based start of the region.
+    pub start: u64,
+    /// The (exclusive) end of the region, or `None` for the end of the chromosome.
+    pub end: Option<u64>,
+}
+
+/// A region with its own target depth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionDepth {
+    /// The region.
+    pub region: Region,
+    /// The target depth within the region.
+    pub depth: u32,
+}
+
+/// The target depth along a chromosome - the depth of the regions that have their own, and a
+/// default depth everywhere else. Where regions overlap, the highest depth is used.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DepthMap {
+    /// The sorted positions at which the depth can change.
+    boundaries: Vec<i64>,
+    /// The depth before each boundary, plus the depth after the last one.
+    depths: Vec<u32>,
+}
+
+impl DepthMap {
+    /// A map with `default` depth everywhere except `regions`.
+    pub(crate) fn new<'a, I>(regions: I, default: u32) -> Self
+    where
+        I: IntoIterator<Item = &'a RegionDepth>,
+    {
+        let regions: Vec<(i64, i64, u32)> = regions
+            .into_iter()
+            .map(|r| {
+                let end = r.region.end.map_or(i64::MAX, |end| end as i64);
+                (r.region.start as i64, end, r.depth)
+            })
+            .collect();
+        let mut boundaries: Vec<i64> = regions
+            .iter()
+            .flat_map(|&(start, end, _)| [start, end])
+            .collect();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut depths: Vec<Option<u32>> = vec![None; boundaries.len() + 1];
+        for (start, end, depth) in regions {
+            let first = boundaries.partition_point(|&b| b <= start);
+            let last = boundaries.partition_point(|&b| b < end);
+            for d in &mut depths[first..=last] {
+                *d = Some(d.map_or(depth, |d| d.max(depth)));
+            }
+        }
+
+        DepthMap {
+            boundaries,
+            depths: depths.into_iter().map(|d| d.unwrap_or(default)).collect(),
+        }
+    }
+
+    /// The target depth at (0-based) `pos`.
+    pub(crate) fn depth_at(&self, pos: i64) -> u32 {
+        self.depths[self.boundaries.partition_point(|&b| b <= pos)]
+    }
+
+    /// The first position after `pos` at which the target depth may change.
+    pub(crate) fn next_boundary(&self, pos: i64) -> Option<i64> {
+        self.boundaries
+            .get(self.boundaries.partition_point(|&b| b <= pos))
+            .copied()
+    }
+}
+
+impl FromStr for Region {
+    type Err = RegionError;
+
+    /// Parses a region in the same form as samtools - `chrom` for a whole chromosome, or
+    /// `chrom:start-end` with a 1-based, inclusive start and end. Commas in the coordinates are
+    /// ignored.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use rasusa::Region;
+    ///
+    /// let region: Region = "chr1:1,001-2,000".parse().unwrap();
+    /// assert_eq!(region.chrom, "chr1");
+    /// assert_eq!(region.start, 1000);
+    /// assert_eq!(region.end, Some(2000));
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || RegionError::InvalidRegion(s.to_string());
+        let s = s.trim();
+        let Some((chrom, range)) = s.rsplit_once(':') else {
+            if s.is_empty() {
+                return Err(err());
+            }
+            return Ok(Region {
+                chrom: s.to_string(),
+                start: 0,
+                end: None,
+            });
+        };
+        let range = range.replace(',', "");
+        let (start, end) = range.split_once('-').ok_or_else(err)?;
+        let start: u64 = start.parse().map_err(|_| err())?;
+        let end: u64 = end.parse().map_err(|_| err())?;
+        if chrom.is_empty() || start == 0 || end < start {
+            return Err(err());
+        }
+        Ok(Region {
+            chrom: chrom.to_string(),
+            start: start - 1,
+            end: Some(end),
+        })
+    }
+}
+
+impl fmt::Display for Region {
+    /// Formats the region in the same form it is parsed from.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            None if self.start == 0 => write!(f, "{}", self.chrom),
+            None => write!(f, "{}:{}-", self.chrom, self.start + 1),
+            Some(end) => write!(f, "{}:{}-{}", self.chrom, self.start + 1, end),
+        }
+    }
+}
+
+/// Parses the regions in a BED file. Only the first three columns are used. Blank lines, comments
+/// and `track`/`browser` lines are skipped.
+pub fn parse_bed<R: BufRead>(reader: R) -> Result<Vec<Region>, RegionError> {
+    parse_bed_with(reader, 3, |region, _| Ok(region))
+}
+
+/// Parses the regions in a BED file along with the target depth for each region in the fourth
+/// column. Lines are skipped in the same way as [`parse_bed`].
+pub fn parse_depth_bed<R: BufRead>(reader: R) -> Result<Vec<RegionDepth>, RegionError> {
+    parse_bed_with(reader, 4, |region, fields| {
+        let depth = fields[3]
+            .trim()
+            .parse()
+            .map_err(|_| "depth is not a positive integer")?;
+        Ok(RegionDepth { region, depth })
+    })
+}
+
+/// Parses each line of a BED file with at least `min_columns` columns into a [`Region`] and
+/// passes it, along with all of the line's fields, to `parse`.
+fn parse_bed_with<R, T, F>(reader: R, min_columns: usize, parse: F) -> Result<Vec<T>, RegionError>
+where
+    R: BufRead,
+    F: Fn(Region, &[&str]) -> Result<T, &'static str>,
+{
+    let mut parsed = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let invalid = |msg: &str| RegionError::InvalidBedLine {
+            line: i + 1,
+            msg: msg.to_string(),
+        };
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < min_columns {
+            return Err(invalid(&format!(
+                "expected at least {} tab-separated columns",
+                min_columns
+            )));
+        }
+        let start: u64 = fields[1]
+            .trim()
+            .parse()
+            .map_err(|_| invalid("start is not a positive integer"))?;
+        let end: u64 = fields[2]
+            .trim()
+            .parse()
+            .map_err(|_| invalid("end is not a positive integer"))?;
+        if end <= start {
+            return Err(invalid("end must be greater than start"));
+        }
+        let region = Region {
+            chrom: fields[0].to_string(),
+            start,
+            end: Some(end),
+        };
+        parsed.push(parse(region, &fields).map_err(invalid)?);
+    }
+    Ok(parsed)
+}
+
+/// Sorts `regions` and merges any on the same chromosome that overlap or are adjacent, so that no
+/// part of a chromosome is subsampled twice.
+pub fn merge_regions(mut regions: Vec<Region>) -> Vec<Region> {
+    regions.sort_by(|a, b| (&a.chrom, a.start).cmp(&(&b.chrom, b.start)));
+    let mut merged: Vec<Region> = Vec::with_capacity(regions.len());
+    for region in regions {
+        if let Some(last) = merged.last_mut() {
+            if last.chrom == region.chrom {
+                match last.end {
+                    None => continue,
+                    Some(last_end) if region.start <= last_end => {
+                        last.end = region.end.map(|end| end.max(last_end));
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        merged.push(region);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(chrom: &str, start: u64, end: Option<u64>) -> Region {
+        Region {
+            chrom: chrom.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn parse_region_with_range() {
+        let actual: Region = "chr1:10-20".parse().unwrap();
+
+        assert_eq!(actual, region("chr1", 9, Some(20)));
+        assert_eq!(actual.to_string(), "chr1:10-20")
+    }
+
+    #[test]
+    fn parse_region_whole_chromosome() {
+        let actual: Region = "plasmid_1".parse().unwrap();
+
+        assert_eq!(actual, region("plasmid_1", 0, None));
+        assert_eq!(actual.to_string(), "plasmid_1")
+    }
+
+    #[test]
+    fn parse_invalid_regions() {
+        for s in ["", "chr1:0-10", "chr1:20-10", "chr1:10", "chr1:a-b", ":1-2"] {
+            assert!(
+                matches!(s.parse::<Region>(), Err(RegionError::InvalidRegion(_))),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parse_bed_skips_headers_and_comments() {
+        let bed = "track name=x\n# comment\nchr1\t0\t100\tname\n\nchr2\t5\t10\n";
+
+        let actual = parse_bed(bed.as_bytes()).unwrap();
+
+        assert_eq!(
+            actual,
+            vec![region("chr1", 0, Some(100)), region("chr2", 5, Some(10))]
+        )
+    }
+
+    #[test]
+    fn parse_bed_with_invalid_line() {
+        let bed = "chr1\t0\t100\nchr1\t100\n";
+
+        let actual = parse_bed(bed.as_bytes()).unwrap_err();
+
+        assert!(matches!(
+            actual,
+            RegionError::InvalidBedLine { line: 2, .. }
+        ))
+    }
+
+    #[test]
+    fn merge_overlapping_and_adjacent_regions() {
+        let regions = vec![
+            region("chr2", 0, Some(10)),
+            region("chr1", 50, Some(60)),
+            region("chr1", 0, Some(10)),
+            region("chr1", 10, Some(20)),
+            region("chr1", 15, Some(18)),
+            region("chr2", 5, None),
+            region("chr2", 20, Some(30)),
+        ];
+
+        let actual = merge_regions(regions);
+        let expected = vec![
+            region("chr1", 0, Some(20)),
+            region("chr1", 50, Some(60)),
+            region("chr2", 0, None),
+        ];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn parse_depth_bed_reads_fourth_column() {
+        let bed = "chr1\t0\t100\t200\nchr2\t5\t10\t0\n";
+
+        let actual = parse_depth_bed(bed.as_bytes()).unwrap();
+        let expected = vec![
+            RegionDepth {
+                region: region("chr1", 0, Some(100)),
+                depth: 200,
+            },
+            RegionDepth {
+                region: region("chr2", 5, Some(10)),
+                depth: 0,
+            },
+        ];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn parse_depth_bed_without_depth_column() {
+        let bed = "chr1\t0\t100\t200\nchr1\t0\t100\n";
+
+        let actual = parse_depth_bed(bed.as_bytes()).unwrap_err();
+
+        assert!(matches!(
+            actual,
+            RegionError::InvalidBedLine { line: 2, .. }
+        ));
+        assert!(parse_depth_bed("chr1\t0\t100\tx\n".as_bytes()).is_err())
+    }
+
+    #[test]
+    fn depth_map_uses_region_depths_and_default() {
+        let regions = vec![
+            RegionDepth {
+                region: region("chr1", 10, Some(20)),
+                depth: 200,
+            },
+            RegionDepth {
+                region: region("chr1", 15, Some(30)),
+                depth: 5,
+            },
+        ];
+        let depths = DepthMap::new(&regions, 30);
+
+        let actual: Vec<u32> = [0, 9, 10, 14, 15, 19, 20, 29, 30, 1000]
+            .iter()
+            .map(|&pos| depths.depth_at(pos))
+            .collect();
+
+        assert_eq!(actual, vec![30, 30, 200, 200, 200, 200, 5, 5, 30, 30]);
+        assert_eq!(depths.next_boundary(0), Some(10));
+        assert_eq!(depths.next_boundary(15), Some(20));
+        assert_eq!(depths.next_boundary(30), None)
+    }
+
+    #[test]
+    fn depth_map_without_regions_is_uniform() {
+        let depths = DepthMap::new(&[], 7);
+
+        assert_eq!(depths.depth_at(0), 7);
+        assert_eq!(depths.depth_at(i64::MAX - 1), 7);
+        assert_eq!(depths.next_boundary(0), None)
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use needletail::parser::LineEnding;
+use tempfile::TempDir;
+
+use crate::fastx::OutputFormat;
+
+/// A record held by the [`ExternalShuffle`](#externalshuffle) until it can be written in order.
+#[derive(Debug, PartialEq)]
+struct RankedRecord {
+    rank: u32,
+    id: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+}
+
+impl RankedRecord {
+    fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.rank.to_le_bytes())?;
+        for field in [Some(&self.id), Some(&self.seq), self.qual.as_ref()] {
+            match field {
+                Some(bytes) => {
+                    w.write_all(&[1])?;
+                    w.write_all(&(bytes.len() as u64).to_le_bytes())?;
+                    w.write_all(bytes)?;
+                }
+                None => w.write_all(&[0])?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next record from `r`, returning `None` at the end of the chunk.
+    fn read_from<R: Read>(r: &mut R) -> std::io::Result<Option<Self>> {
+        let mut rank = [0u8; 4];
+        match r.read_exact(&mut rank) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+
+        let mut read_field = || -> std::io::Result<Option<Vec<u8>>> {
+            let mut present = [0u8; 1];
+            r.read_exact(&mut present)?;
+            if present[0] == 0 {
+                return Ok(None);
+            }
+            let mut len = [0u8; 8];
+            r.read_exact(&mut len)?;
+            let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+            r.read_exact(&mut bytes)?;
+            Ok(Some(bytes))
+        };
+
+        Ok(Some(RankedRecord {
+            rank: u32::from_le_bytes(rank),
+            id: read_field()?.unwrap_or_default(),
+            seq: read_field()?.unwrap_or_default(),
+            qual: read_field()?,
+        }))
+    }
+}
+
+/// Writes records in the order of their rank, while holding at most `chunk_size` records in
+/// memory at once.
+///
+/// Records are spread over temporary files so that each file holds a contiguous range of ranks.
+/// They are buffered in memory and, whenever the buffer is full, appended to their files - which
+/// are opened one at a time. Each file is then sorted in memory and written out in turn. If all
+/// records fit within a single chunk, no temporary files are used.
+pub struct ExternalShuffle {
+    chunk_size: usize,
+    nb_chunks: usize,
+    /// The records not yet written to their chunk.
+    buffer: Vec<RankedRecord>,
+    /// The directory holding the chunks. It is deleted when dropped.
+    dir: Option<TempDir>,
+}
+
+impl ExternalShuffle {
+    /// Create a shuffler for `nb_records` records with ranks in the range `0..nb_records`.
+    pub fn new(nb_records: usize, chunk_size: usize) -> std::io::Result<Self> {
+        let chunk_size = chunk_size.max(1);
+        let nb_chunks = nb_records.div_ceil(chunk_size);
+        let dir = match nb_chunks {
+            0 | 1 => None,
+            _ => Some(tempfile::Builder::new().prefix("rasusa").tempdir()?),
+        };
+        Ok(ExternalShuffle {
+            chunk_size,
+            nb_chunks,
+            buffer: Vec::with_capacity(nb_records.min(chunk_size)),
+            dir,
+        })
+    }
+
+    /// Add a record that should be written at position `rank` in the output.
+    pub fn push(
+        &mut self,
+        rank: u32,
+        id: &[u8],
+        seq: &[u8],
+        qual: Option<&[u8]>,
+    ) -> std::io::Result<()> {
+        self.buffer.push(RankedRecord {
+            rank,
+            id: id.to_vec(),
+            seq: seq.to_vec(),
+            qual: qual.map(|q| q.to_vec()),
+        });
+        if self.dir.is_some() && self.buffer.len() >= self.chunk_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Write all records to `write_to` in order of their rank.
+    pub fn write_into<W: Write>(
+        mut self,
+        write_to: &mut W,
+        output_format: &OutputFormat,
+    ) -> std::io::Result<()> {
+        if self.dir.is_none() {
+            return write_sorted(self.buffer, write_to, output_format);
+        }
+
+        self.spill()?;
+        for i in 0..self.nb_chunks {
+            let path = self.chunk_path(i);
+            // no record has a rank in this chunk's range
+            if !path.exists() {
+                continue;
+            }
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut records = vec![];
+            while let Some(record) = RankedRecord::read_from(&mut reader)? {
+                records.push(record);
+            }
+            write_sorted(records, write_to, output_format)?;
+        }
+        Ok(())
+    }
+
+    fn chunk_path(&self, i: usize) -> PathBuf {
+        let dir = self
+            .dir
+            .as_ref()
+            .expect("chunks are only used with a directory");
+        dir.path().join(format!("chunk{}", i))
+    }
+
+    /// Append the buffered records to the chunks their ranks fall in, one chunk at a time.
+    fn spill(&mut self) -> std::io::Result<()> {
+        let mut records = std::mem::take(&mut self.buffer);
+        records.sort_unstable_by_key(|r| r.rank);
+        let mut records = records.into_iter().peekable();
+        while let Some(first) = records.next() {
+            let i = first.rank as usize / self.chunk_size;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.chunk_path(i))?;
+            let mut writer = BufWriter::new(file);
+            first.write_to(&mut writer)?;
+            while let Some(record) = records.next_if(|r| r.rank as usize / self.chunk_size == i) {
+                record.write_to(&mut writer)?;
+            }
+            writer.flush()?;
+        }
+        self.buffer = Vec::with_capacity(self.chunk_size);
+        Ok(())
+    }
+}
+
+fn write_sorted<W: Write>(
+    mut records: Vec<RankedRecord>,
+    write_to: &mut W,
+    output_format: &OutputFormat,
+) -> std::io::Result<()> {
+    records.sort_unstable_by_key(|r| r.rank);
+    for r in records {
+        output_format.write_record(&r.id, &r.seq, r.qual.as_deref(), LineEnding::Unix, write_to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shuffle_and_collect(ranks: &[u32], chunk_size: usize) -> String {
+        let mut shuffler = ExternalShuffle::new(ranks.len(), chunk_size).unwrap();
+        for &rank in ranks {
+            let id = format!("read{}", rank);
+            shuffler.push(rank, id.as_bytes(), b"ACGT", None).unwrap();
+        }
+        let mut out: Vec<u8> = vec![];
+        shuffler
+            .write_into(&mut out, &OutputFormat::default())
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ranked_record_roundtrip() {
+        let record = RankedRecord {
+            rank: 7,
+            id: b"read1 comment".to_vec(),
+            seq: b"ACGT".to_vec(),
+            qual: Some(b"!!!!".to_vec()),
+        };
+        let mut buf: Vec<u8> = vec![];
+        record.write_to(&mut buf).unwrap();
+
+        let mut reader = buf.as_slice();
+        let actual = RankedRecord::read_from(&mut reader).unwrap();
+
+        assert_eq!(actual, Some(record));
+        assert_eq!(RankedRecord::read_from(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn in_memory_shuffle_writes_in_rank_order() {
+        let actual = shuffle_and_collect(&[2, 0, 1], 10);
+        let expected = ">read0\nACGT\n>read1\nACGT\n>read2\nACGT\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn external_shuffle_writes_in_rank_order() {
+        let actual = shuffle_and_collect(&[4, 2, 0, 1, 3], 2);
+        let expected = ">read0\nACGT\n>read1\nACGT\n>read2\nACGT\n>read3\nACGT\n>read4\nACGT\n";
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn external_shuffle_with_many_chunks_writes_in_rank_order() {
+        let mut ranks: Vec<u32> = (0..1000).collect();
+        ranks.reverse();
+        ranks.swap(10, 500);
+        let actual = shuffle_and_collect(&ranks, 3);
+        let expected: String = (0..1000).map(|i| format!(">read{}\nACGT\n", i)).collect();
+
+        assert_eq!(actual, expected)
+    }
+}
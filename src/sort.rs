@@ -0,0 +1,291 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::{self, Format, Read};
+use tempfile::TempDir;
+
+/// The number of records [`RecordSorter`] holds in memory before spilling them to a temporary
+/// file.
+pub const SORT_BUFFER_RECORDS: usize = 500_000;
+
+/// A record held by the [`RecordSorter`], ordered by its coordinate and then by the order it was
+/// added in, so records with the same coordinate keep their order.
+struct SortRecord {
+    key: (u32, i64, u64),
+    record: bam::Record,
+}
+
+impl SortRecord {
+    fn new(record: bam::Record, rank: u64) -> Self {
+        // unmapped records without a position sort last
+        let tid = if record.tid() < 0 {
+            u32::MAX
+        } else {
+            record.tid() as u32
+        };
+        SortRecord {
+            key: (tid, record.pos(), rank),
+            record,
+        }
+    }
+}
+
+impl PartialEq for SortRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for SortRecord {}
+
+impl PartialOrd for SortRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortRecord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Writes records in coordinate order when they are added nearly in order.
+///
+/// Records are held in a reorder buffer until [`flush_before`](#method.flush_before) says no
+/// record added later can start before them. If the buffer grows beyond `max_records`, it is
+/// spilled to a sorted temporary file and all of the files are merged by
+/// [`finish`](#method.finish).
+pub struct RecordSorter {
+    header: bam::Header,
+    max_records: usize,
+    buffer: BinaryHeap<Reverse<SortRecord>>,
+    nb_added: u64,
+    chunks: Vec<std::path::PathBuf>,
+    /// The directory holding the chunks. It is deleted when dropped.
+    dir: Option<TempDir>,
+}
+
+impl RecordSorter {
+    /// Create a sorter whose temporary files, if any, are written with `header`.
+    pub fn new(header: &bam::Header, max_records: usize) -> Self {
+        RecordSorter {
+            header: header.clone(),
+            max_records: max_records.max(1),
+            buffer: BinaryHeap::new(),
+            nb_added: 0,
+            chunks: vec![],
+            dir: None,
+        }
+    }
+
+    /// Add a record to be written in coordinate order.
+    pub fn push(&mut self, record: bam::Record) -> Result<()> {
+        self.buffer
+            .push(Reverse(SortRecord::new(record, self.nb_added)));
+        self.nb_added += 1;
+        if self.buffer.len() >= self.max_records {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered records that start before `pos` on `tid`, or on an earlier chromosome.
+    /// The caller guarantees no record added later starts before this point.
+    ///
+    /// Once records have been spilled to temporary files, nothing is written until
+    /// [`finish`](#method.finish).
+    pub fn flush_before(&mut self, tid: u32, pos: i64, writer: &mut bam::Writer) -> Result<()> {
+        if !self.chunks.is_empty() {
+            return Ok(());
+        }
+        while self
+            .buffer
+            .peek()
+            .is_some_and(|Reverse(r)| (r.key.0, r.key.1) < (tid, pos))
+        {
+            let Reverse(r) = self.buffer.pop().expect("peeked record is present");
+            writer.write(&r.record).context("Failed to write record")?;
+        }
+        Ok(())
+    }
+
+    /// Write all remaining records in coordinate order.
+    pub fn finish(mut self, writer: &mut bam::Writer) -> Result<()> {
+        if self.chunks.is_empty() {
+            while let Some(Reverse(r)) = self.buffer.pop() {
+                writer.write(&r.record).context("Failed to write record")?;
+            }
+            return Ok(());
+        }
+
+        self.spill()?;
+        let mut readers = self
+            .chunks
+            .iter()
+            .map(|path| bam::Reader::from_path(path).context("Failed to read sort chunk"))
+            .collect::<Result<Vec<_>>>()?;
+        // the rank of a merged record is the index of its chunk, so ties keep their order
+        let mut heads = BinaryHeap::new();
+        for (i, reader) in readers.iter_mut().enumerate() {
+            if let Some(record) = next_record(reader)? {
+                heads.push(Reverse(SortRecord::new(record, i as u64)));
+            }
+        }
+        while let Some(Reverse(head)) = heads.pop() {
+            writer
+                .write(&head.record)
+                .context("Failed to write record")?;
+            let i = head.key.2;
+            if let Some(record) = next_record(&mut readers[i as usize])? {
+                heads.push(Reverse(SortRecord::new(record, i)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the buffered records, in order, to a new temporary file.
+    fn spill(&mut self) -> Result<()> {
+        if self.dir.is_none() {
+            let dir = tempfile::Builder::new()
+                .prefix("rasusa")
+                .tempdir()
+                .context("Failed to create temporary directory for sorting")?;
+            self.dir = Some(dir);
+        }
+        let dir = self.dir.as_ref().expect("directory was just created");
+        let path = dir.path().join(format!("chunk{}.bam", self.chunks.len()));
+        {
+            let mut writer = bam::Writer::from_path(&path, &self.header, Format::Bam)
+                .context("Failed to create sort chunk")?;
+            writer
+                .set_compression_level(bam::CompressionLevel::Fastest)
+                .context("Failed to set sort chunk compression")?;
+            while let Some(Reverse(r)) = self.buffer.pop() {
+                writer
+                    .write(&r.record)
+                    .context("Failed to write sort chunk")?;
+            }
+        }
+        self.chunks.push(path);
+        Ok(())
+    }
+}
+
+fn next_record(reader: &mut bam::Reader) -> Result<Option<bam::Record>> {
+    let mut record = bam::Record::new();
+    match reader.read(&mut record) {
+        None => Ok(None),
+        Some(result) => {
+            result.context("Failed to read sort chunk")?;
+            Ok(Some(record))
+        }
+    }
+}
+
+/// A copy of `header` with its sort order (`SO` in the `@HD` line) set to `sort_order`.
+pub fn with_sort_order(header: &bam::HeaderView, sort_order: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(header.as_bytes());
+    let mut lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    let so = format!("SO:{}", sort_order);
+    match lines.first_mut() {
+        Some(hd) if hd.starts_with("@HD") => {
+            // a sub-sort or grouping only makes sense alongside the original sort order
+            let mut fields: Vec<&str> = hd
+                .split('\t')
+                .filter(|f| !["SO:", "SS:", "GO:"].iter().any(|tag| f.starts_with(tag)))
+                .collect();
+            fields.push(&so);
+            *hd = fields.join("\t");
+        }
+        _ => lines.insert(0, format!("@HD\tVN:1.6\t{}", so)),
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "@HD\tVN:1.6\tSO:unsorted\tGO:query\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n@PG\tID:test\n";
+
+    fn header() -> bam::Header {
+        bam::Header::from_template(&bam::HeaderView::from_bytes(HEADER.as_bytes()))
+    }
+
+    fn record(name: &str, tid: i32, pos: i64) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", &[30; 4]);
+        record.set_tid(tid);
+        record.set_pos(pos);
+        record
+    }
+
+    /// Sorts `records`, flushing after each one as if nothing later starts before it, and returns
+    /// the names of the records in the output.
+    fn sort_and_collect(records: &[(&str, i32, i64)], max_records: usize) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bam");
+        let header = header();
+        {
+            let mut writer = bam::Writer::from_path(&path, &header, Format::Bam).unwrap();
+            let mut sorter = RecordSorter::new(&header, max_records);
+            for &(name, tid, pos) in records {
+                sorter.push(record(name, tid, pos)).unwrap();
+                sorter
+                    .flush_before(tid as u32, pos - 10, &mut writer)
+                    .unwrap();
+            }
+            sorter.finish(&mut writer).unwrap();
+        }
+        let mut reader = bam::Reader::from_path(&path).unwrap();
+        reader
+            .records()
+            .map(|r| String::from_utf8(r.unwrap().qname().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sorter_reorders_within_window() {
+        let records = [
+            ("b", 0, 105),
+            ("a", 0, 100),
+            ("c", 0, 108),
+            ("d", 0, 130),
+            ("e", 0, 150),
+            ("f", 1, 5),
+            ("g", 1, 5),
+        ];
+        let expected = vec!["a", "b", "c", "d", "e", "f", "g"];
+
+        assert_eq!(sort_and_collect(&records, 100), expected);
+        // spilling to temporary files gives the same order
+        assert_eq!(sort_and_collect(&records, 2), expected);
+    }
+
+    #[test]
+    fn with_sort_order_replaces_existing_sort_order() {
+        let view = bam::HeaderView::from_bytes(HEADER.as_bytes());
+        let header = with_sort_order(&view, "coordinate");
+        let text = String::from_utf8(header.to_bytes()).unwrap();
+
+        assert!(text.starts_with("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1"));
+        assert!(text.ends_with("@PG\tID:test"));
+    }
+
+    #[test]
+    fn with_sort_order_adds_missing_hd_line() {
+        let view = bam::HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+        let header = with_sort_order(&view, "coordinate");
+        let text = String::from_utf8(header.to_bytes()).unwrap();
+
+        assert_eq!(text, "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000");
+    }
+}
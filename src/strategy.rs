@@ -0,0 +1,486 @@
+use std::collections::HashSet;
+
+use rand::prelude::*;
+
+/// Per-read metadata gathered from the input that a [`SamplingStrategy`] bases its selection on.
+/// Strategies that need anything else about the reads, such as their names, hold it themselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadMetadata<'a> {
+    /// The length of each read.
+    pub lengths: &'a [u32],
+}
+
+impl<'a> ReadMetadata<'a> {
+    /// Metadata for reads with the given `lengths`.
+    pub fn from_lengths(lengths: &'a [u32]) -> Self {
+        ReadMetadata { lengths }
+    }
+
+    /// The number of reads.
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// Whether there are no reads.
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+}
+
+/// A method of selecting a subset of reads.
+///
+/// Implementors only need to provide [`ordered_indices`](#tymethod.ordered_indices); the mask
+/// and count returned by [`indices`](#method.indices) are derived from it.
+pub trait SamplingStrategy: std::fmt::Debug + Send + Sync {
+    /// Returns the indices of the selected reads, in the order they were selected.
+    fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32>;
+
+    /// Returns a mask over all reads indicating which were selected, along with the number of
+    /// reads selected.
+    fn indices(&self, reads: &ReadMetadata) -> (Vec<bool>, usize) {
+        let selected = self.ordered_indices(reads);
+
+        (selection_mask(&selected, reads.len()), selected.len())
+    }
+}
+
+/// Converts the indices of selected reads into a mask over all `nb_reads` reads.
+pub fn selection_mask(selected: &[u32], nb_reads: usize) -> Vec<bool> {
+    let mut to_keep: Vec<bool> = vec![false; nb_reads];
+    for &i in selected {
+        to_keep[i as usize] = true;
+    }
+    to_keep
+}
+
+/// Returns the indices `0..n`, but shuffled. If `seed` is `None`, the random number generator is
+/// seeded by the operating system.
+///
+/// # Note
+///
+/// If the file has more than 4,294,967,296 reads, this function's behaviour is undefined.
+pub fn shuffled_indices(n: usize, seed: Option<u64>) -> Vec<u32> {
+    let mut indices: Vec<u32> = (0..n as u32).collect();
+    let mut rng = match seed {
+        Some(s) => rand_pcg::Pcg64::seed_from_u64(s),
+        None => rand_pcg::Pcg64::seed_from_u64(random()),
+    };
+
+    indices.shuffle(&mut rng);
+    indices
+}
+
+/// Randomly select reads until the total number of bases reaches a target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UniformBases {
+    /// Number of bases to sub-sample down to.
+    pub target_total_bases: u64,
+    /// Random seed to use for sub-sampling.
+    pub seed: Option<u64>,
+}
+
+impl SamplingStrategy for UniformBases {
+    fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32> {
+        let mut indices = shuffled_indices(reads.len(), self.seed);
+        let mut total_bases_kept: u64 = 0;
+
+        let nb_reads_to_keep = indices
+            .iter()
+            .take_while(|&&idx| {
+                let keep = total_bases_kept < self.target_total_bases;
+                total_bases_kept += u64::from(reads.lengths[idx as usize]);
+                keep
+            })
+            .count();
+
+        indices.truncate(nb_reads_to_keep);
+        indices
+    }
+}
+
+/// Randomly select a fixed number of reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UniformCount {
+    /// Number of reads to subsample down to.
+    pub num_reads: u64,
+    /// Random seed to use for sub-sampling.
+    pub seed: Option<u64>,
+}
+
+impl SamplingStrategy for UniformCount {
+    fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32> {
+        let mut indices = shuffled_indices(reads.len(), self.seed);
+        indices.truncate((self.num_reads as usize).min(reads.len()));
+        indices
+    }
+}
+
+/// How much a [`TopUp`] or [`NameHash`] should select in total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// Select reads until the total number of bases reaches this.
+    Bases(u64),
+    /// Select this many reads.
+    Reads(u64),
+    /// Select this fraction of the reads.
+    Fraction(f64),
+}
+
+/// Extend a previous selection of reads by randomly selecting previously unselected reads until
+/// the target is reached.
+///
+/// All previously selected reads are always kept, even if they exceed the target on their own, so
+/// the new selection is guaranteed to contain the old one. Reads are matched by name, so the
+/// strategy holds the name of each read it selects from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopUp {
+    /// Names of the previously selected reads.
+    pub previous: HashSet<Vec<u8>>,
+    /// The name of each read to select from, in order.
+    pub names: Vec<Vec<u8>>,
+    /// The total to select, including the previous selection.
+    pub target: Target,
+    /// Random seed to use for sub-sampling.
+    pub seed: Option<u64>,
+}
+
+impl SamplingStrategy for TopUp {
+    fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32> {
+        // previous reads come first, but still in a random order
+        let (mut indices, rest): (Vec<u32>, Vec<u32>) = shuffled_indices(reads.len(), self.seed)
+            .into_iter()
+            .partition(|&i| {
+                self.names
+                    .get(i as usize)
+                    .is_some_and(|name| self.previous.contains(name))
+            });
+
+        let mut total_bases: u64 = indices
+            .iter()
+            .map(|&i| u64::from(reads.lengths[i as usize]))
+            .sum();
+        for i in rest {
+            let reached = match self.target {
+                Target::Bases(target) => total_bases >= target,
+                Target::Reads(target) => indices.len() as u64 >= target,
+                Target::Fraction(frac) => indices.len() as f64 >= frac * reads.len() as f64,
+            };
+            if reached {
+                break;
+            }
+            indices.push(i);
+            total_bases += u64::from(reads.lengths[i as usize]);
+        }
+        indices
+    }
+}
+
+/// Select reads based on a seeded hash of their name, so the same reads are selected regardless
+/// of the order of the input - e.g., from re-sorted or re-chunked copies of a file.
+///
+/// With [`Target::Fraction`], each read is kept if its hash falls below that fraction of the hash
+/// range, so the decision for a read doesn't depend on any other read. For the other targets,
+/// reads are selected in order of their hash until the target is reached. A trailing `/1` or `/2`
+/// is ignored so that mates get the same hash. The strategy holds the name of each read it selects
+/// from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameHash {
+    /// The name of each read to select from, in order.
+    pub names: Vec<Vec<u8>>,
+    /// How much to select.
+    pub target: Target,
+    /// Seed for the hash.
+    pub seed: u64,
+}
+
+impl NameHash {
+    /// The seeded hash of a read `name`.
+    pub fn hash(&self, name: &[u8]) -> u64 {
+        let name = match name {
+            [rest @ .., b'/', b'1' | b'2'] => rest,
+            _ => name,
+        };
+        // FNV-1a, with a final mix to spread similar names across the whole range
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325 ^ mix(self.seed);
+        for &b in name {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        mix(hash)
+    }
+}
+
+/// The splitmix64 finaliser.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl SamplingStrategy for NameHash {
+    fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32> {
+        let mut hashes: Vec<(u64, u32)> = self
+            .names
+            .iter()
+            .take(reads.len())
+            .enumerate()
+            .map(|(i, name)| (self.hash(name), i as u32))
+            .collect();
+        hashes.sort_unstable();
+
+        let mut total_bases: u64 = 0;
+        let nb_reads_to_keep = match self.target {
+            Target::Fraction(frac) => {
+                let threshold = frac * u64::MAX as f64;
+                hashes
+                    .iter()
+                    .take_while(|&&(hash, _)| frac >= 1.0 || (hash as f64) < threshold)
+                    .count()
+            }
+            Target::Reads(n) => (n as usize).min(hashes.len()),
+            Target::Bases(target) => hashes
+                .iter()
+                .take_while(|&&(_, idx)| {
+                    let keep = total_bases < target;
+                    total_bases += u64::from(reads.lengths[idx as usize]);
+                    keep
+                })
+                .count(),
+        };
+
+        hashes
+            .into_iter()
+            .take(nb_reads_to_keep)
+            .map(|(_, idx)| idx)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffled_indices_for_empty_vector_returns_empty() {
+        let actual = shuffled_indices(0, None);
+
+        assert!(actual.is_empty())
+    }
+
+    #[test]
+    fn shuffled_indices_for_vector_len_one_returns_zero() {
+        let actual = shuffled_indices(1, None);
+        let expected: Vec<u32> = vec![0];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn shuffled_indices_for_vector_len_two_does_shuffle() {
+        let mut num_times_shuffled = 0;
+        let iterations = 500;
+        for _ in 0..iterations {
+            let idxs = shuffled_indices(2, None);
+            if idxs == vec![1, 0] {
+                num_times_shuffled += 1;
+            }
+        }
+
+        // chances of shuffling the same way 100 times in a row is 3.054936363499605e-151
+        assert!(num_times_shuffled > 0 && num_times_shuffled < iterations)
+    }
+
+    #[test]
+    fn shuffled_indices_with_seed_produces_same_ordering() {
+        let idxs1 = shuffled_indices(3, Some(1));
+        let idxs2 = shuffled_indices(3, Some(1));
+
+        assert_eq!(idxs1, idxs2)
+    }
+
+    #[test]
+    fn selection_mask_marks_selected_reads() {
+        let actual = selection_mask(&[3, 0], 4);
+        let expected = vec![true, false, false, true];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn uniform_bases_stops_once_target_reached() {
+        let lengths: Vec<u32> = vec![10, 10, 10, 10];
+        let strategy = UniformBases {
+            target_total_bases: 15,
+            seed: Some(5),
+        };
+
+        let (mask, nb_select) = strategy.indices(&ReadMetadata::from_lengths(&lengths));
+
+        assert_eq!(nb_select, 2);
+        assert_eq!(mask.iter().filter(|&&b| b).count(), 2)
+    }
+
+    #[test]
+    fn uniform_count_selects_in_shuffled_order() {
+        let lengths: Vec<u32> = vec![1, 2, 3, 4, 5];
+        let strategy = UniformCount {
+            num_reads: 3,
+            seed: Some(5),
+        };
+
+        let actual = strategy.ordered_indices(&ReadMetadata::from_lengths(&lengths));
+        let expected = shuffled_indices(5, Some(5))[..3].to_vec();
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn custom_strategy_gets_default_indices() {
+        /// Selects every read longer than 2bp
+        #[derive(Debug)]
+        struct LongerThanTwo;
+
+        impl SamplingStrategy for LongerThanTwo {
+            fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32> {
+                (0..reads.len() as u32)
+                    .filter(|&i| reads.lengths[i as usize] > 2)
+                    .collect()
+            }
+        }
+
+        let lengths: Vec<u32> = vec![1, 5, 2, 3];
+        let (mask, nb_select) = LongerThanTwo.indices(&ReadMetadata::from_lengths(&lengths));
+
+        assert_eq!(mask, vec![false, true, false, true]);
+        assert_eq!(nb_select, 2)
+    }
+
+    #[test]
+    fn uniform_bases_with_same_seed_and_higher_target_is_superset() {
+        let lengths: Vec<u32> = (1..=100).collect();
+        let reads = ReadMetadata::from_lengths(&lengths);
+        let low = UniformBases {
+            target_total_bases: 500,
+            seed: Some(3),
+        };
+        let high = UniformBases {
+            target_total_bases: 2000,
+            seed: Some(3),
+        };
+
+        let low_selected = low.ordered_indices(&reads);
+        let high_selected = high.ordered_indices(&reads);
+
+        assert!(high_selected.len() > low_selected.len());
+        assert!(low_selected.iter().all(|i| high_selected.contains(i)))
+    }
+
+    fn names(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| format!("read{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn top_up_keeps_previous_and_adds_until_target() {
+        let lengths: Vec<u32> = vec![10; 10];
+        let previous: HashSet<Vec<u8>> = [b"read2".to_vec(), b"read7".to_vec()].into();
+        let strategy = TopUp {
+            previous,
+            names: names(10),
+            target: Target::Bases(45),
+            seed: Some(11),
+        };
+
+        let actual = strategy.ordered_indices(&ReadMetadata::from_lengths(&lengths));
+
+        assert_eq!(actual.len(), 5);
+        let mut first_two = actual[..2].to_vec();
+        first_two.sort_unstable();
+        assert_eq!(first_two, vec![2, 7])
+    }
+
+    #[test]
+    fn top_up_with_target_below_previous_keeps_all_previous() {
+        let lengths: Vec<u32> = vec![10; 4];
+        let previous: HashSet<Vec<u8>> = [b"read0".to_vec(), b"read3".to_vec()].into();
+        let strategy = TopUp {
+            previous,
+            names: names(4),
+            target: Target::Reads(1),
+            seed: None,
+        };
+
+        let (mask, nb_select) = strategy.indices(&ReadMetadata::from_lengths(&lengths));
+
+        assert_eq!(nb_select, 2);
+        assert_eq!(mask, vec![true, false, false, true])
+    }
+
+    #[test]
+    fn name_hash_selection_is_independent_of_order() {
+        let lengths: Vec<u32> = vec![10; 100];
+        let names = names(100);
+        let mut reversed = names.clone();
+        reversed.reverse();
+        let selected_names = |names: &[Vec<u8>]| -> HashSet<Vec<u8>> {
+            let strategy = NameHash {
+                names: names.to_vec(),
+                target: Target::Bases(300),
+                seed: 4,
+            };
+            strategy
+                .ordered_indices(&ReadMetadata::from_lengths(&lengths))
+                .into_iter()
+                .map(|i| names[i as usize].clone())
+                .collect()
+        };
+
+        let forward = selected_names(&names);
+        assert_eq!(forward.len(), 30);
+        assert_eq!(forward, selected_names(&reversed))
+    }
+
+    #[test]
+    fn name_hash_fraction_decides_each_read_independently() {
+        let names = names(1000);
+        let selected = |names: &[Vec<u8>]| -> Vec<Vec<u8>> {
+            let lengths = vec![1; names.len()];
+            let strategy = NameHash {
+                names: names.to_vec(),
+                target: Target::Fraction(0.25),
+                seed: 0,
+            };
+            let mut selected: Vec<Vec<u8>> = strategy
+                .ordered_indices(&ReadMetadata::from_lengths(&lengths))
+                .into_iter()
+                .map(|i| names[i as usize].clone())
+                .collect();
+            selected.sort();
+            selected
+        };
+
+        let all = selected(&names);
+        let mut chunked = selected(&names[..400]);
+        chunked.extend(selected(&names[400..]));
+        chunked.sort();
+
+        assert!(all.len() > 200 && all.len() < 300);
+        assert_eq!(all, chunked)
+    }
+
+    #[test]
+    fn name_hash_ignores_mate_suffix_and_depends_on_seed() {
+        let strategy = NameHash {
+            names: vec![],
+            target: Target::Reads(1),
+            seed: 1,
+        };
+        let other_seed = NameHash {
+            seed: 2,
+            ..strategy.clone()
+        };
+
+        assert_eq!(strategy.hash(b"read1/1"), strategy.hash(b"read1/2"));
+        assert_eq!(strategy.hash(b"read1/1"), strategy.hash(b"read1"));
+        assert_ne!(strategy.hash(b"read1"), other_seed.hash(b"read1"))
+    }
+}
@@ -1,103 +1,129 @@
-use rand::prelude::*;
+use thiserror::Error;
+
+use std::collections::HashSet;
+
+use crate::strategy::{
+    NameHash, ReadMetadata, SamplingStrategy, Target, TopUp, UniformBases, UniformCount,
+};
+
+/// A collection of custom errors relating to setting up sub-sampling.
+#[derive(Error, Debug, PartialEq)]
+pub enum SamplingError {
+    /// Indicates that neither or both of a target number of bases and number of reads were given.
+    #[error("Exactly one of a target number of bases or a number of reads must be given")]
+    InvalidTargetCombination,
+}
 
 /// A `Struct` for dealing with the randomised part of sub-sampling.
+///
+/// The selection itself is delegated to a [`SamplingStrategy`].
+#[derive(Debug)]
 pub struct SubSampler {
-    /// Number of bases to sub-sample down to.    
-    pub target_total_bases: Option<u64>,
-    /// Random seed to use for sub-sampling. If `None` is used, then the random number generator
-    /// will be seeded by the operating system.
-    pub seed: Option<u64>,
-    /// Number of reads to subsample down to
-    pub num_reads: Option<u64>,
+    strategy: Box<dyn SamplingStrategy>,
 }
 
 impl SubSampler {
-    /// Returns a vector of indices for elements in `v`, but shuffled.
+    /// Create a `SubSampler` that randomly selects reads until either `target_total_bases` or
+    /// `num_reads` is reached. If `seed` is `None`, then the random number generator will be
+    /// seeded by the operating system.
     ///
-    /// # Note
-    ///
-    /// If the file has more than 4,294,967,296 reads, this function's behaviour is undefined.
-    ///
-    /// # Example
+    /// # Errors
+    /// A [`SamplingError::InvalidTargetCombination`] is returned if neither, or both, of
+    /// `target_total_bases` and `num_reads` are given.
+    pub fn new(
+        target_total_bases: Option<u64>,
+        num_reads: Option<u64>,
+        seed: Option<u64>,
+    ) -> Result<Self, SamplingError> {
+        match (target_total_bases, num_reads) {
+            (Some(target_total_bases), None) => Ok(Self::from_strategy(UniformBases {
+                target_total_bases,
+                seed,
+            })),
+            (None, Some(num_reads)) => Ok(Self::from_strategy(UniformCount { num_reads, seed })),
+            _ => Err(SamplingError::InvalidTargetCombination),
+        }
+    }
+
+    /// Create a `SubSampler` that extends a `previous` selection of read names with randomly
+    /// selected reads until either `target_total_bases` or `num_reads` is reached. The previous
+    /// reads are always kept. `names` holds the name of each read to select from, in the same
+    /// order as the lengths later given to the `SubSampler`.
     ///
-    /// ```rust
-    /// let v: Vec<u64> = vec![55, 1];
-    /// let sampler = SubSampler {
-    ///     target_total_bases: 100,
-    ///     seed: None,
-    /// };
-    /// let mut num_times_shuffled = 0;
-    /// let iterations = 500;
-    /// for _ in 0..iterations {
-    ///     let idxs = sampler.shuffled_indices(&v);
-    ///     if idxs == vec![1, 0] {
-    ///         num_times_shuffled += 1;
-    ///     }
-    /// }
-    /// // chances of shuffling the same way 100 times in a row is 3.054936363499605e-151
-    /// assert!(num_times_shuffled > 0 && num_times_shuffled < iterations)
-    /// ```
-    fn shuffled_indices<T>(&self, v: &[T]) -> Vec<u32> {
-        let mut indices: Vec<u32> = (0..v.len() as u32).collect();
-        let mut rng = match self.seed {
-            Some(s) => rand_pcg::Pcg64::seed_from_u64(s),
-            None => rand_pcg::Pcg64::seed_from_u64(random()),
+    /// # Errors
+    /// A [`SamplingError::InvalidTargetCombination`] is returned if neither, or both, of
+    /// `target_total_bases` and `num_reads` are given.
+    pub fn top_up(
+        previous: HashSet<Vec<u8>>,
+        names: Vec<Vec<u8>>,
+        target_total_bases: Option<u64>,
+        num_reads: Option<u64>,
+        seed: Option<u64>,
+    ) -> Result<Self, SamplingError> {
+        let target = match (target_total_bases, num_reads) {
+            (Some(bases), None) => Target::Bases(bases),
+            (None, Some(n)) => Target::Reads(n),
+            _ => return Err(SamplingError::InvalidTargetCombination),
         };
+        Ok(Self::from_strategy(TopUp {
+            previous,
+            names,
+            target,
+            seed,
+        }))
+    }
 
-        indices.shuffle(&mut rng);
-        indices
+    /// Create a `SubSampler` that selects reads by a hash of their name, seeded with `seed`, so the
+    /// same reads are selected regardless of the order of the input. `names` holds the name of
+    /// each read to select from, in the same order as the lengths later given to the `SubSampler`.
+    /// See [`NameHash`].
+    pub fn name_hash(target: Target, seed: u64, names: Vec<Vec<u8>>) -> Self {
+        Self::from_strategy(NameHash {
+            names,
+            target,
+            seed,
+        })
     }
 
-    /// Sub-samples `lengths` to the desired `target_total_bases` specified in the `SubSampler` and
-    /// returns the indices for the reads that were selected.
+    /// Create a `SubSampler` that uses a custom `strategy`.
+    pub fn from_strategy<S: SamplingStrategy + 'static>(strategy: S) -> Self {
+        SubSampler {
+            strategy: Box::new(strategy),
+        }
+    }
+
+    /// Sub-samples `lengths` to the target specified in the `SubSampler` and returns a mask of
+    /// the reads that were selected, along with how many were selected.
     ///
     /// # Example
     ///
     /// ```rust
+    /// use rasusa::SubSampler;
+    ///
     /// let v: Vec<u32> = vec![50, 50, 50];
-    /// let sampler = SubSampler {
-    ///     target_total_bases: 100,
-    ///     seed: Some(1),
-    /// };
-    /// let actual = sampler.indices(&v);
+    /// let sampler = SubSampler::new(Some(100), None, Some(1)).unwrap();
+    /// let (actual, nb_select) = sampler.indices(&v);
     ///
     /// assert_eq!(actual.len(), 3);
+    /// assert_eq!(nb_select, 2);
+    /// assert!(!actual[0]);
     /// assert!(actual[1]);
     /// assert!(actual[2]);
-    /// assert!(actual[3]);
     /// ```
     pub fn indices(&self, lengths: &[u32]) -> (Vec<bool>, usize) {
-        let mut indices = self.shuffled_indices(lengths).into_iter();
-        let mut to_keep: Vec<bool> = vec![false; lengths.len()];
-        let mut total_bases_kept: u64 = 0;
-
-        let mut nb_reads_to_keep = 0;
-        match (self.target_total_bases, self.num_reads) {
-            (Some(ttb), None) => {
-                while total_bases_kept < ttb {
-                    let idx = match indices.next() {
-                        Some(i) => i as usize,
-                        None => break,
-                    };
-                    to_keep[idx] = true;
-                    total_bases_kept += u64::from(lengths[idx.to_owned()]);
-                    nb_reads_to_keep += 1;
-                }
-            }
-            (None, Some(n_reads)) => {
-                nb_reads_to_keep = (n_reads as usize).min(indices.len());
-                if nb_reads_to_keep == indices.len() {
-                    to_keep.fill(true);
-                } else {
-                    for i in &indices.as_slice()[0..nb_reads_to_keep] {
-                        to_keep[*i as usize] = true;
-                    }
-                }
-            }
-            _ => panic!("Subsampler::inices got an unexpected combination. Please report this bug"),
-        }
+        self.strategy.indices(&ReadMetadata::from_lengths(lengths))
+    }
+
+    /// Sub-samples `lengths` in the same way as [`indices`](#method.indices), but returns the
+    /// indices of the selected reads in the (random) order they were selected.
+    pub fn ordered_indices(&self, lengths: &[u32]) -> Vec<u32> {
+        self.select(&ReadMetadata::from_lengths(lengths))
+    }
 
-        (to_keep, nb_reads_to_keep)
+    /// Returns the indices of the selected reads, in the order they were selected, based on all
+    /// available metadata for the reads.
+    pub fn select(&self, reads: &ReadMetadata) -> Vec<u32> {
+        self.strategy.ordered_indices(reads)
     }
 }
 
@@ -105,87 +131,10 @@ impl SubSampler {
 mod tests {
     use super::*;
 
-    #[test]
-    fn shuffled_indices_for_empty_vector_returns_empty() {
-        let v: Vec<u64> = Vec::new();
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
-
-        let actual = sampler.shuffled_indices(&v);
-
-        assert!(actual.is_empty())
-    }
-
-    #[test]
-    fn shuffled_indices_for_vector_len_one_returns_zero() {
-        let v: Vec<u64> = vec![55];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
-
-        let actual = sampler.shuffled_indices(&v);
-        let expected: Vec<u32> = vec![0];
-
-        assert_eq!(actual, expected)
-    }
-
-    #[test]
-    fn shuffled_indices_for_vector_len_two_does_shuffle() {
-        let v: Vec<u64> = vec![55, 1];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
-
-        let mut num_times_shuffled = 0;
-        let iterations = 500;
-        for _ in 0..iterations {
-            let idxs = sampler.shuffled_indices(&v);
-            if idxs == vec![1, 0] {
-                num_times_shuffled += 1;
-            }
-        }
-
-        // chances of shuffling the same way 100 times in a row is 3.054936363499605e-151
-        assert!(num_times_shuffled > 0 && num_times_shuffled < iterations)
-    }
-
-    #[test]
-    fn shuffled_indices_with_seed_produces_same_ordering() {
-        let v: Vec<u64> = vec![55, 1, 8];
-        let sampler1 = SubSampler {
-            target_total_bases: Some(100),
-            seed: Some(1),
-            num_reads: None,
-        };
-
-        let sampler2 = SubSampler {
-            target_total_bases: Some(100),
-            seed: Some(1),
-            num_reads: None,
-        };
-        let idxs1 = sampler1.shuffled_indices(&v);
-        let idxs2 = sampler2.shuffled_indices(&v);
-
-        for i in 0..idxs1.len() {
-            assert_eq!(idxs1[i], idxs2[i])
-        }
-    }
-
     #[test]
     fn subsample_empty_lengths_returns_empty() {
         let v: Vec<u32> = Vec::new();
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, None).unwrap();
 
         let (_, nb_select) = sampler.indices(&v);
 
@@ -195,11 +144,7 @@ mod tests {
     #[test]
     fn subsample_one_length_target_zero_returns_empty() {
         let v: Vec<u32> = Vec::new();
-        let sampler = SubSampler {
-            target_total_bases: Some(0),
-            seed: None,
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(0), None, None).unwrap();
 
         let (_, nb_select) = sampler.indices(&v);
 
@@ -209,11 +154,7 @@ mod tests {
     #[test]
     fn subsample_one_read_target_zero_returns_empty() {
         let v: Vec<u32> = Vec::new();
-        let sampler = SubSampler {
-            target_total_bases: None,
-            seed: None,
-            num_reads: Some(5),
-        };
+        let sampler = SubSampler::new(None, Some(5), None).unwrap();
 
         let (_, nb_select) = sampler.indices(&v);
 
@@ -223,11 +164,7 @@ mod tests {
     #[test]
     fn subsample_one_length_less_than_target_returns_zero() {
         let v: Vec<u32> = vec![5];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, None).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
 
@@ -238,11 +175,7 @@ mod tests {
     #[test]
     fn subsample_more_reads_than_available_takes_all() {
         let v: Vec<u32> = vec![5];
-        let sampler = SubSampler {
-            target_total_bases: None,
-            seed: None,
-            num_reads: Some(10),
-        };
+        let sampler = SubSampler::new(None, Some(10), None).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
 
@@ -253,11 +186,7 @@ mod tests {
     #[test]
     fn subsample_num_reads_and_available_equal_takes_all() {
         let v: Vec<u32> = vec![5, 5, 66];
-        let sampler = SubSampler {
-            target_total_bases: None,
-            seed: None,
-            num_reads: Some(3),
-        };
+        let sampler = SubSampler::new(None, Some(3), None).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
 
@@ -268,11 +197,7 @@ mod tests {
     #[test]
     fn subsample_num_reads_less_than_available_takes_subset() {
         let v: Vec<u32> = vec![5, 5, 66];
-        let sampler = SubSampler {
-            target_total_bases: None,
-            seed: None,
-            num_reads: Some(2),
-        };
+        let sampler = SubSampler::new(None, Some(2), None).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
 
@@ -285,11 +210,7 @@ mod tests {
     #[test]
     fn subsample_one_length_greater_than_target_returns_zero() {
         let v: Vec<u32> = vec![500];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, None).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
 
@@ -300,11 +221,7 @@ mod tests {
     #[test]
     fn subsample_three_lengths_sum_greater_than_target_returns_two() {
         let v: Vec<u32> = vec![50, 50, 50];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: Some(1),
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, Some(1)).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
 
@@ -317,11 +234,7 @@ mod tests {
     #[test]
     fn subsample_three_lengths_sum_less_than_target_returns_three() {
         let v: Vec<u32> = vec![5, 5, 5];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, None).unwrap();
 
         let (actual, _) = sampler.indices(&v);
         let expected = vec![true; 3];
@@ -332,11 +245,7 @@ mod tests {
     #[test]
     fn subsample_three_lengths_sum_equal_target_returns_three() {
         let v: Vec<u32> = vec![25, 25, 50];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: None,
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, None).unwrap();
 
         let (actual, _) = sampler.indices(&v);
         let expected = vec![true; 3];
@@ -347,11 +256,7 @@ mod tests {
     #[test]
     fn subsample_three_lengths_all_greater_than_target_returns_two() {
         let v: Vec<u32> = vec![500, 500, 500];
-        let sampler = SubSampler {
-            target_total_bases: Some(100),
-            seed: Some(1),
-            num_reads: None,
-        };
+        let sampler = SubSampler::new(Some(100), None, Some(1)).unwrap();
 
         let (actual, nb_select) = sampler.indices(&v);
         println!("{:?}", actual);
@@ -361,4 +266,84 @@ mod tests {
         assert!(!actual[1]);
         assert!(actual[2]);
     }
+
+    #[test]
+    fn ordered_indices_matches_indices() {
+        let v: Vec<u32> = vec![50, 50, 50, 10, 5];
+        let sampler = SubSampler::new(Some(100), None, Some(4)).unwrap();
+
+        let (mask, nb_select) = sampler.indices(&v);
+        let ordered = sampler.ordered_indices(&v);
+
+        assert_eq!(ordered.len(), nb_select);
+        for (i, keep) in mask.iter().enumerate() {
+            assert_eq!(*keep, ordered.contains(&(i as u32)));
+        }
+    }
+
+    #[test]
+    fn both_bases_and_num_reads_is_an_error() {
+        let actual = SubSampler::new(Some(100), Some(5), None).unwrap_err();
+
+        assert_eq!(actual, SamplingError::InvalidTargetCombination)
+    }
+
+    #[test]
+    fn neither_bases_nor_num_reads_is_an_error() {
+        let actual = SubSampler::new(None, None, Some(1)).unwrap_err();
+
+        assert_eq!(actual, SamplingError::InvalidTargetCombination)
+    }
+
+    #[test]
+    fn subsampler_from_custom_strategy() {
+        #[derive(Debug)]
+        struct FirstRead;
+
+        impl SamplingStrategy for FirstRead {
+            fn ordered_indices(&self, reads: &ReadMetadata) -> Vec<u32> {
+                if reads.is_empty() {
+                    vec![]
+                } else {
+                    vec![0]
+                }
+            }
+        }
+
+        let sampler = SubSampler::from_strategy(FirstRead);
+        let (actual, nb_select) = sampler.indices(&[5, 6]);
+
+        assert_eq!(nb_select, 1);
+        assert_eq!(actual, vec![true, false])
+    }
+
+    #[test]
+    fn top_up_indices_from_lengths_keep_previous_reads() {
+        let lengths: Vec<u32> = vec![10; 6];
+        let names: Vec<Vec<u8>> = (0..6).map(|i| format!("read{}", i).into_bytes()).collect();
+        let previous: HashSet<Vec<u8>> = [b"read1".to_vec(), b"read4".to_vec()].into();
+        let sampler = SubSampler::top_up(previous, names, None, Some(3), Some(2)).unwrap();
+
+        let (actual, nb_select) = sampler.indices(&lengths);
+
+        assert_eq!(nb_select, 3);
+        assert!(actual[1] && actual[4])
+    }
+
+    #[test]
+    fn name_hash_indices_from_lengths_select_by_name() {
+        let lengths: Vec<u32> = vec![10; 6];
+        let names: Vec<Vec<u8>> = (0..6).map(|i| format!("read{}", i).into_bytes()).collect();
+        let mut reversed = names.clone();
+        reversed.reverse();
+        let sampler = SubSampler::name_hash(Target::Reads(3), 1, names);
+        let reversed_sampler = SubSampler::name_hash(Target::Reads(3), 1, reversed);
+
+        let (actual, nb_select) = sampler.indices(&lengths);
+        let (mut reversed_actual, _) = reversed_sampler.indices(&lengths);
+        reversed_actual.reverse();
+
+        assert_eq!(nb_select, 3);
+        assert_eq!(actual, reversed_actual)
+    }
 }
@@ -0,0 +1,403 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use needletail::parser::LineEnding;
+use rust_htslib::bam;
+use rust_htslib::bam::{Format, Read};
+
+use crate::alignment::{infer_format_from_path, set_keep_tag};
+use crate::fastx::OutputFormat;
+
+/// The highest base quality that can be written as a fastq quality character (`~`).
+const MAX_FASTQ_QUAL: u8 = b'~' - 33;
+
+/// A `Struct` used for dealing with unaligned SAM/BAM/CRAM files - e.g., those produced by ONT
+/// and PacBio basecallers.
+#[derive(Debug, PartialEq)]
+pub struct Ubam {
+    /// The path for the file.
+    path: PathBuf,
+}
+
+impl Ubam {
+    /// Create a `Ubam` object from a `std::path::Path`.
+    pub fn from_path(path: &Path) -> Self {
+        Ubam {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Whether `path` has a SAM/BAM/CRAM extension and should be treated as an unaligned
+    /// alignment file rather than fasta/fastq.
+    pub fn is_ubam_path(path: &Path) -> bool {
+        infer_format_from_path(path).is_some()
+    }
+
+    fn reader(&self) -> Result<bam::Reader> {
+        bam::Reader::from_path(&self.path)
+            .context(format!("Failed to read unaligned file {:?}", self.path))
+    }
+
+    /// Create a writer for `output` in `format` with the same header as this file, so that all
+    /// read groups and program lines are carried over.
+    pub fn create(&self, output: Option<&Path>, format: Format) -> Result<bam::Writer> {
+        let reader = self.reader()?;
+        let header = bam::Header::from_template(reader.header());
+        let writer = match output {
+            Some(path) => bam::Writer::from_path(path, &header, format),
+            None => bam::Writer::from_stdout(&header, format),
+        };
+        writer.context("Failed to create output alignment file")
+    }
+
+    /// Returns a vector containing the lengths of all the reads in the file.
+    pub fn read_lengths(&self) -> Result<Vec<u32>> {
+        let mut reader = self.reader()?;
+        let mut read_lengths: Vec<u32> = vec![];
+        let mut record = bam::Record::new();
+
+        while let Some(result) = reader.read(&mut record) {
+            result.context("Failed to parse record")?;
+            read_lengths.push(record.seq_len() as u32);
+        }
+        Ok(read_lengths)
+    }
+
+    /// Returns a vector containing the names of all the reads in the file.
+    pub fn read_ids(&self) -> Result<Vec<Vec<u8>>> {
+        let mut reader = self.reader()?;
+        let mut read_ids: Vec<Vec<u8>> = vec![];
+        let mut record = bam::Record::new();
+
+        while let Some(result) = reader.read(&mut record) {
+            result.context("Failed to parse record")?;
+            read_ids.push(record.qname().to_vec());
+        }
+        Ok(read_ids)
+    }
+
+    /// Writes records, with indices contained within `reads_to_keep`, to `writer`. Records are
+    /// written as-is, so all tags (e.g., MM/ML) are preserved. If `unselected` is given, all other
+    /// records are written to it in the same pass.
+    pub fn filter_reads_into(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        writer: &mut bam::Writer,
+        mut unselected: Option<&mut bam::Writer>,
+    ) -> Result<usize> {
+        let read_all = unselected.is_some();
+        self.filter_reads(reads_to_keep, nb_reads_keep, read_all, |record, keep| {
+            let writer = match (keep, unselected.as_mut()) {
+                (true, _) => &mut *writer,
+                (false, Some(unselected)) => &mut **unselected,
+                (false, None) => return Ok(()),
+            };
+            writer.write(record).context("Failed to write record")
+        })
+    }
+
+    /// Writes records, with indices contained within `reads_to_keep`, to `write_to` as fastq.
+    /// Records without base qualities are written as fasta. If `unselected` is given, all other
+    /// records are written to it, in its format, in the same pass.
+    pub fn filter_reads_into_fastq<T: Write>(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        write_to: &mut T,
+        output_format: &OutputFormat,
+        mut unselected: Option<(&mut dyn Write, &OutputFormat)>,
+    ) -> Result<usize> {
+        let read_all = unselected.is_some();
+        self.filter_reads(reads_to_keep, nb_reads_keep, read_all, |record, keep| {
+            let (writer, format): (&mut dyn Write, &OutputFormat) =
+                match (keep, unselected.as_mut()) {
+                    (true, _) => (&mut *write_to, output_format),
+                    (false, Some((unselected_to, unselected_format))) => {
+                        (&mut **unselected_to, *unselected_format)
+                    }
+                    (false, None) => return Ok(()),
+                };
+            write_fastx_record(record, format, None, writer)
+        })
+    }
+
+    /// Writes every record to `writer`, with [`KEEP_AUX_TAG`](crate::KEEP_AUX_TAG)
+    /// indicating whether its index is contained within `reads_to_keep`.
+    pub fn tag_reads_into(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        writer: &mut bam::Writer,
+    ) -> Result<usize> {
+        self.filter_reads(reads_to_keep, nb_reads_keep, true, |record, keep| {
+            let mut record = record.clone();
+            set_keep_tag(&mut record, keep)?;
+            writer.write(&record).context("Failed to write record")
+        })
+    }
+
+    /// Writes every record to `write_to` as fastq, with a
+    /// [`KEEP_COMMENT`](crate::KEEP_COMMENT) header comment indicating whether its index is
+    /// contained within `reads_to_keep`.
+    pub fn tag_reads_into_fastq<T: Write>(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        write_to: &mut T,
+        output_format: &OutputFormat,
+    ) -> Result<usize> {
+        self.filter_reads(reads_to_keep, nb_reads_keep, true, |record, keep| {
+            write_fastx_record(record, output_format, Some(keep), write_to)
+        })
+    }
+
+    /// Calls `write_record` with each selected record. Unless `read_all` is set, reading stops
+    /// once all selected records have been seen; otherwise, `write_record` is also called with
+    /// the unselected records.
+    fn filter_reads<F>(
+        &self,
+        reads_to_keep: &[bool],
+        nb_reads_keep: usize,
+        read_all: bool,
+        mut write_record: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&bam::Record, bool) -> Result<()>,
+    {
+        let mut reader = self.reader()?;
+        let mut record = bam::Record::new();
+        let mut total_len = 0;
+        let mut read_idx: usize = 0;
+        let mut nb_reads_written = 0;
+
+        while read_all || nb_reads_written < nb_reads_keep {
+            match reader.read(&mut record) {
+                None => break,
+                Some(result) => result.context("Failed to parse record")?,
+            }
+            let keep = reads_to_keep.get(read_idx).copied().unwrap_or(false);
+            if keep {
+                total_len += record.seq_len();
+                nb_reads_written += 1;
+            }
+            if keep || read_all {
+                write_record(&record, keep)?;
+            }
+            read_idx += 1;
+        }
+
+        if nb_reads_written == nb_reads_keep {
+            Ok(total_len)
+        } else {
+            Err(anyhow!("Some expected indices were not in the input file"))
+        }
+    }
+}
+
+/// Writes `record` to `writer` as fastq, or fasta if it has no base qualities. If `keep` is
+/// given, the record is tagged with whether it was selected.
+fn write_fastx_record(
+    record: &bam::Record,
+    format: &OutputFormat,
+    keep: Option<bool>,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let seq = record.seq().as_bytes();
+    let qual = record.qual();
+    // htslib uses 0xff to indicate the base qualities are missing
+    let qual: Option<Vec<u8>> = match qual.first() {
+        Some(&q) if q != 0xff => Some(
+            qual.iter()
+                .map(|&q| match q {
+                    0..=MAX_FASTQ_QUAL => Ok(q + 33),
+                    _ => Err(anyhow!(
+                        "Read {} has base quality {}, but the highest that can be written as fastq is {}",
+                        String::from_utf8_lossy(record.qname()),
+                        q,
+                        MAX_FASTQ_QUAL
+                    )),
+                })
+                .collect::<Result<_>>()?,
+        ),
+        _ => None,
+    };
+    let id = record.qname();
+    let result = match keep {
+        None => format.write_record(id, &seq, qual.as_deref(), LineEnding::Unix, writer),
+        Some(keep) => {
+            format.write_tagged_record(id, &seq, qual.as_deref(), LineEnding::Unix, keep, writer)
+        }
+    };
+    result.context("Failed to write record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alignment::KEEP_AUX_TAG;
+    use rust_htslib::bam::record::Aux;
+    use tempfile::Builder;
+
+    const SAM: &str = "tests/cases/unaligned.sam";
+
+    #[test]
+    fn ubam_from_path_recognises_extensions() {
+        assert!(Ubam::is_ubam_path(Path::new("reads.bam")));
+        assert!(Ubam::is_ubam_path(Path::new("reads.cram")));
+        assert!(!Ubam::is_ubam_path(Path::new("reads.fq.gz")));
+    }
+
+    #[test]
+    fn get_read_lengths_for_unaligned_sam() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+
+        let actual = ubam.read_lengths().unwrap();
+        let expected: Vec<u32> = vec![8, 4, 6];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn get_read_ids_for_unaligned_sam() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+
+        let actual = ubam.read_ids().unwrap();
+        let expected: Vec<Vec<u8>> = vec![b"read1".to_vec(), b"read2".to_vec(), b"read3".to_vec()];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn filter_reads_into_fastq_converts_records() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+        let reads_to_keep = vec![false, true, true];
+        let mut out: Vec<u8> = vec![];
+
+        let total_len = ubam
+            .filter_reads_into_fastq(&reads_to_keep, 2, &mut out, &OutputFormat::default(), None)
+            .unwrap();
+
+        let actual = String::from_utf8(out).unwrap();
+        let expected = "@read2\nGGCC\n+\n5555\n>read3\nTTTTAA\n";
+
+        assert_eq!(total_len, 10);
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn filter_reads_into_fastq_with_unselected() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+        let reads_to_keep = vec![false, true, false];
+        let mut out: Vec<u8> = vec![];
+        let mut unselected: Vec<u8> = vec![];
+        let unselected_format = OutputFormat::default();
+
+        ubam.filter_reads_into_fastq(
+            &reads_to_keep,
+            1,
+            &mut out,
+            &OutputFormat::default(),
+            Some((&mut unselected, &unselected_format)),
+        )
+        .unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "@read2\nGGCC\n+\n5555\n");
+        assert!(String::from_utf8(unselected)
+            .unwrap()
+            .starts_with("@read1\n"));
+    }
+
+    #[test]
+    fn filter_reads_into_bam_keeps_tags() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+        let output = Builder::new().suffix(".bam").tempfile().unwrap();
+        let reads_to_keep = vec![true, false, false];
+        {
+            let mut writer = ubam.create(Some(output.path()), Format::Bam).unwrap();
+            ubam.filter_reads_into(&reads_to_keep, 1, &mut writer, None)
+                .unwrap();
+        }
+
+        let mut reader = bam::Reader::from_path(output.path()).unwrap();
+        let records: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].qname(), b"read1");
+        assert!(records[0].aux(b"MM").is_ok());
+        assert!(records[0].aux(b"ch").is_ok());
+    }
+
+    #[test]
+    fn tag_reads_into_bam_tags_every_record() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+        let output = Builder::new().suffix(".bam").tempfile().unwrap();
+        let reads_to_keep = vec![false, true, false];
+        {
+            let mut writer = ubam.create(Some(output.path()), Format::Bam).unwrap();
+            ubam.tag_reads_into(&reads_to_keep, 1, &mut writer).unwrap();
+        }
+
+        let mut reader = bam::Reader::from_path(output.path()).unwrap();
+        let keep: Vec<bool> = reader
+            .records()
+            .map(|r| matches!(r.unwrap().aux(KEEP_AUX_TAG), Ok(Aux::U8(1))))
+            .collect();
+
+        assert_eq!(keep, reads_to_keep);
+    }
+
+    #[test]
+    fn tag_reads_into_fastq_adds_comment() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+        let mut out: Vec<u8> = vec![];
+
+        ubam.tag_reads_into_fastq(&[false, true, false], 1, &mut out, &OutputFormat::default())
+            .unwrap();
+
+        let headers: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter(|l| l.starts_with('@') || l.starts_with('>'))
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                "@read1 rasusa:keep=0",
+                "@read2 rasusa:keep=1",
+                ">read3 rasusa:keep=0"
+            ]
+        );
+    }
+
+    #[test]
+    fn write_fastx_record_rejects_out_of_range_qualities() {
+        let mut record = bam::Record::new();
+        record.set(b"read1", None, b"ACGT", &[30, 93, 94, 255]);
+        let mut out: Vec<u8> = vec![];
+
+        let actual = write_fastx_record(&record, &OutputFormat::default(), None, &mut out);
+
+        assert!(actual.unwrap_err().to_string().contains("base quality 94"));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn filter_reads_index_out_of_range() {
+        let ubam = Ubam::from_path(Path::new(SAM));
+        let reads_to_keep = vec![false, false, false, true];
+        let mut out: Vec<u8> = vec![];
+
+        let actual = ubam.filter_reads_into_fastq(
+            &reads_to_keep,
+            1,
+            &mut out,
+            &OutputFormat::default(),
+            None,
+        );
+
+        assert!(actual.is_err())
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Applies `f` to every item, using up to `nb_workers` threads. The results are in the same order
+/// as `items`.
+pub(crate) fn par_map<I, T, F>(items: &[I], nb_workers: usize, f: F) -> Vec<T>
+where
+    I: Sync,
+    T: Send,
+    F: Fn(&I) -> T + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<T>>> = Mutex::new((0..items.len()).map(|_| None).collect());
+    let nb_workers = nb_workers.max(1).min(items.len());
+
+    std::thread::scope(|scope| {
+        for _ in 0..nb_workers {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(idx) else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap()[idx] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item is processed"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn par_map_keeps_the_order_of_the_items() {
+        let items: Vec<u32> = (0..100).collect();
+
+        let actual = par_map(&items, 4, |i| i * 2);
+        let expected: Vec<u32> = items.iter().map(|i| i * 2).collect();
+
+        assert_eq!(actual, expected)
+    }
+}
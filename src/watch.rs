@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::{debug, info};
+
+use crate::cli::{check_path_exists, parse_compression_format, parse_level, Coverage, GenomeSize};
+use crate::fastx::{default_compression_level, OutputFormat};
+use crate::strategy::selection_mask;
+use crate::{Fastx, Runner, SubSampler};
+
+/// Extensions (ignoring any compression extension) of the files picked up in the watched directory.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+pub struct Watch {
+    /// Directory that fast{a,q} chunks are being written to - e.g., an ONT fastq_pass directory
+    #[arg(value_parser = check_path_exists, name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Output filepath for the subsampled reads. Defaults to stdout
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Genome size to calculate coverage with respect to. e.g., 4.3kb, 7Tb, 9000, 4.1MB
+    #[arg(short, long, value_name = "size|faidx")]
+    pub genome_size: GenomeSize,
+
+    /// The desired depth of coverage to subsample the reads to
+    #[arg(short, long, value_name = "FLOAT")]
+    pub coverage: Coverage,
+
+    /// Keep watching until the coverage exceeds the target by this fraction - e.g., 0.1 waits
+    /// for 10% more than the target
+    ///
+    /// Without a margin, the subsample would be (nearly) all of the reads seen so far
+    #[arg(short, long, value_name = "FLOAT", default_value_t = 0.1)]
+    pub margin: f64,
+
+    /// Seconds between checks of the directory for new files
+    #[arg(short, long, value_name = "SECS", default_value_t = 10.0)]
+    pub interval: f64,
+
+    /// Give up if the target is not reached within this many seconds
+    #[arg(short, long, value_name = "SECS")]
+    pub timeout: Option<f64>,
+
+    /// Random seed to use.
+    #[arg(short, long, value_name = "INT")]
+    pub seed: Option<u64>,
+
+    /// u: uncompressed; b: Bzip2; g: Gzip; l: Lzma; x: Xz (Lzma); z: Zstd
+    ///
+    /// Rasusa will attempt to infer the output compression format automatically from the filename
+    /// extension. This option is used to override that. If writing to stdout, the default is
+    /// uncompressed
+    #[arg(short = 'O', long, value_name = "u|b|g|l|x|z", value_parser = parse_compression_format)]
+    pub output_type: Option<niffler::compression::Format>,
+
+    /// Compression level to use if compressing output. Uses the default level for the format if
+    /// not specified.
+    #[arg(short = 'l', long, value_parser = parse_level, value_name = "1-21")]
+    pub compress_level: Option<niffler::Level>,
+}
+
+/// Incrementally tracks the read lengths of the fast{a,q} files in a directory.
+///
+/// A file is only read once its size is the same on two consecutive polls, so files that are
+/// still being written are not read part way through.
+#[derive(Debug, Default)]
+pub struct DirectoryTracker {
+    dir: PathBuf,
+    /// Sizes of files seen on the last poll that have not been read yet.
+    pending: BTreeMap<PathBuf, u64>,
+    /// Files that have been read, and their read lengths, in the order they were read.
+    files: Vec<(PathBuf, Vec<u32>)>,
+    read: HashSet<PathBuf>,
+    total_bases: u64,
+}
+
+impl DirectoryTracker {
+    pub fn new(dir: &Path) -> Self {
+        DirectoryTracker {
+            dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Total number of bases in the files read so far.
+    pub fn total_bases(&self) -> u64 {
+        self.total_bases
+    }
+
+    /// The files read so far, with their read lengths.
+    pub fn files(&self) -> &[(PathBuf, Vec<u32>)] {
+        &self.files
+    }
+
+    /// Checks the directory for new files and reads those that have finished being written.
+    /// Returns the number of bases added.
+    pub fn poll(&mut self) -> Result<u64> {
+        let mut current: BTreeMap<PathBuf, u64> = BTreeMap::new();
+        for entry in std::fs::read_dir(&self.dir)
+            .context(format!("Failed to read directory {:?}", self.dir))?
+        {
+            let entry = entry.context("Failed to read directory entry")?;
+            let path = entry.path();
+            if !Fastx::is_fastx_path(&path) || self.read.contains(&path) {
+                continue;
+            }
+            let metadata = entry.metadata().context("Failed to get file metadata")?;
+            if metadata.is_file() {
+                current.insert(path, metadata.len());
+            }
+        }
+
+        let mut new_bases = 0;
+        for (path, size) in &current {
+            if self.pending.get(path) != Some(size) {
+                continue;
+            }
+            let lengths = Fastx::from_path(path)
+                .read_lengths()
+                .context(format!("Failed to gather read lengths for {:?}", path))?;
+            let bases: u64 = lengths.iter().map(|&l| u64::from(l)).sum();
+            debug!(
+                "Read {} reads ({} bases) from {:?}",
+                lengths.len(),
+                bases,
+                path
+            );
+            new_bases += bases;
+            self.read.insert(path.clone());
+            self.files.push((path.clone(), lengths));
+        }
+        current.retain(|path, _| !self.read.contains(path));
+        self.pending = current;
+        self.total_bases += new_bases;
+
+        Ok(new_bases)
+    }
+}
+
+impl Watch {
+    fn create_output(&self) -> Result<Box<dyn Write>> {
+        match &self.output {
+            Some(path) => Ok(Fastx::from_path(path)
+                .create(self.compress_level, self.output_type)
+                .context("unable to create output file")?),
+            None => match self.output_type {
+                None => Ok(Box::new(stdout())),
+                Some(fmt) => Ok(niffler::basic::get_writer(
+                    Box::new(stdout()),
+                    fmt,
+                    self.compress_level
+                        .unwrap_or_else(|| default_compression_level(fmt)),
+                )?),
+            },
+        }
+    }
+
+    /// Subsamples the reads in the files read by `tracker` to `target_total_bases` and writes
+    /// them to `output`. Returns the number of bases written.
+    fn subsample_into<W: Write>(
+        &self,
+        tracker: &DirectoryTracker,
+        target_total_bases: u64,
+        output: &mut W,
+    ) -> Result<usize> {
+        let lengths: Vec<u32> = tracker
+            .files()
+            .iter()
+            .flat_map(|(_, lengths)| lengths.iter().copied())
+            .collect();
+        let subsampler = SubSampler::new(Some(target_total_bases), None, self.seed)?;
+        let order = subsampler.ordered_indices(&lengths);
+        let reads_to_keep = selection_mask(&order, lengths.len());
+        info!("Keeping {} of {} reads", order.len(), lengths.len());
+
+        let mut offset = 0;
+        let mut kept_bases = 0;
+        for (path, file_lengths) in tracker.files() {
+            let mask = &reads_to_keep[offset..offset + file_lengths.len()];
+            offset += file_lengths.len();
+            let nb_keep = mask.iter().filter(|&&keep| keep).count();
+            if nb_keep == 0 {
+                continue;
+            }
+            kept_bases += Fastx::from_path(path)
+                .filter_reads_into(mask, nb_keep, output, &OutputFormat::default())
+                .context(format!("unable to subsample {:?}", path))?;
+        }
+        Ok(kept_bases)
+    }
+}
+
+impl Runner for Watch {
+    fn run(&mut self) -> Result<()> {
+        if !self.dir.is_dir() {
+            return Err(anyhow!("{:?} is not a directory", self.dir));
+        }
+        if self.margin < 0.0 || self.interval < 0.0 {
+            return Err(anyhow!("--margin and --interval can't be negative"));
+        }
+
+        let target_total_bases = self.genome_size * self.coverage;
+        let stop_at = (target_total_bases as f64 * (1.0 + self.margin)).ceil() as u64;
+        info!(
+            "Watching {:?} until there are more than {} bases ({} target + {}% margin)",
+            self.dir,
+            stop_at,
+            target_total_bases,
+            self.margin * 100.0
+        );
+
+        let mut tracker = DirectoryTracker::new(&self.dir);
+        let start = Instant::now();
+        loop {
+            if tracker.poll()? > 0 {
+                info!(
+                    "{} files read with a total of {} bases ({:.2}x)",
+                    tracker.files().len(),
+                    tracker.total_bases(),
+                    tracker.total_bases() as f64 / f64::from(self.genome_size)
+                );
+            }
+            if tracker.total_bases() > stop_at {
+                break;
+            }
+            if let Some(timeout) = self.timeout {
+                if start.elapsed().as_secs_f64() >= timeout {
+                    return Err(anyhow!(
+                        "Target not reached within {} seconds - only {} of {} bases seen",
+                        timeout,
+                        tracker.total_bases(),
+                        stop_at
+                    ));
+                }
+            }
+            sleep(Duration::from_secs_f64(self.interval));
+        }
+
+        // the output is only created once the target is reached, so a run that stops early
+        // doesn't leave behind an empty output that looks like a subsample
+        let mut output = self.create_output()?;
+        let kept_bases = self.subsample_into(&tracker, target_total_bases, &mut output)?;
+        info!(
+            "Actual coverage of kept reads is {:.2}x",
+            kept_bases as f64 / f64::from(self.genome_size)
+        );
+
+        info!("Done 🎉");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_reads(path: &Path, nb_reads: usize) {
+        let reads: String = (0..nb_reads)
+            .map(|i| format!("@read{}\nACGTACGTAC\n+\nIIIIIIIIII\n", i))
+            .collect();
+        std::fs::write(path, reads).unwrap();
+    }
+
+    #[test]
+    fn tracker_reads_files_once_they_stop_changing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tracker = DirectoryTracker::new(dir.path());
+        write_reads(&dir.path().join("a.fq"), 2);
+        std::fs::write(dir.path().join("summary.txt"), "not reads").unwrap();
+
+        // first sighting of a.fq
+        assert_eq!(tracker.poll().unwrap(), 0);
+        // a.fq hasn't changed so it is read
+        assert_eq!(tracker.poll().unwrap(), 20);
+
+        write_reads(&dir.path().join("b.fq"), 1);
+        assert_eq!(tracker.poll().unwrap(), 0);
+        // b.fq grows between polls, so is not read yet
+        write_reads(&dir.path().join("b.fq"), 3);
+        assert_eq!(tracker.poll().unwrap(), 0);
+        assert_eq!(tracker.poll().unwrap(), 30);
+        // nothing new
+        assert_eq!(tracker.poll().unwrap(), 0);
+
+        assert_eq!(tracker.total_bases(), 50);
+        let files: Vec<usize> = tracker.files().iter().map(|(_, l)| l.len()).collect();
+        assert_eq!(files, vec![2, 3]);
+    }
+
+    #[test]
+    fn tracker_reads_every_fastx_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tracker = DirectoryTracker::new(dir.path());
+        write_reads(&dir.path().join("a.ffn"), 1);
+        write_reads(&dir.path().join("b.FASTQ"), 2);
+
+        tracker.poll().unwrap();
+
+        assert_eq!(tracker.poll().unwrap(), 30);
+    }
+
+    #[test]
+    fn subsample_into_spans_all_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_reads(&dir.path().join("a.fq"), 5);
+        write_reads(&dir.path().join("b.fq"), 5);
+        let mut tracker = DirectoryTracker::new(dir.path());
+        tracker.poll().unwrap();
+        tracker.poll().unwrap();
+
+        let watch = Watch::try_parse_from([
+            "watch",
+            dir.path().to_str().unwrap(),
+            "-g",
+            "10",
+            "-c",
+            "3",
+            "-s",
+            "1",
+        ])
+        .unwrap();
+        let mut out: Vec<u8> = vec![];
+        let kept = watch.subsample_into(&tracker, 30, &mut out).unwrap();
+
+        assert_eq!(kept, 30);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 12)
+    }
+}
@@ -133,7 +133,7 @@ fn unequal_number_of_reads_raises_error() -> Result<(), Box<dyn std::error::Erro
     ]);
 
     cmd.assert().failure().stderr(predicate::str::contains(
-        "Illumina files are assumed to have the same number of reads",
+        "linked input files are assumed to have the same number of reads",
     ));
 
     Ok(())
@@ -200,3 +200,632 @@ fn frac_from_each_with_paired_reads() -> Result<(), Box<dyn std::error::Error>>
 
     Ok(())
 }
+
+#[test]
+fn three_linked_inputs_are_subsampled_in_lockstep() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let outs: Vec<_> = (1..=3)
+        .map(|i| dir.path().join(format!("out{}.fq", i)))
+        .collect();
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![
+        READS,
+        "tests/cases/r1.fq.gz",
+        "tests/cases/r2.fq.gz",
+        "tests/cases/r1.fq.gz",
+        "-b",
+        "4",
+        "--target-files",
+        "2",
+        "-s",
+        "1",
+    ]);
+    for out in &outs {
+        cmd.arg("-o").arg(out);
+    }
+
+    cmd.assert().success();
+
+    let contents: Vec<String> = outs
+        .iter()
+        .map(std::fs::read_to_string)
+        .collect::<Result<_, _>>()?;
+    // only the second file counts towards the target, so a single 4bp read is enough
+    assert_eq!(contents[0].lines().count(), 4);
+    let name = contents[0].lines().next().unwrap().trim_end_matches("/1");
+    for c in &contents {
+        assert!(c.starts_with(name));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn three_inputs_with_three_outputs_stay_in_lockstep() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let outs: Vec<_> = (1..=3)
+        .map(|i| dir.path().join(format!("out{}.fq", i)))
+        .collect();
+    for seed in ["1", "2", "3"] {
+        let mut cmd = Command::cargo_bin(BIN)?;
+        cmd.args(vec![
+            READS,
+            "tests/cases/r1.fq.gz",
+            "tests/cases/r2.fq.gz",
+            "tests/cases/r1.fq.gz",
+            "-n",
+            "1",
+            "-s",
+            seed,
+        ]);
+        for out in &outs {
+            cmd.arg("-o").arg(out);
+        }
+
+        cmd.assert().success();
+
+        let names: Vec<String> = outs
+            .iter()
+            .map(|out| {
+                let contents = std::fs::read_to_string(out)?;
+                assert_eq!(contents.lines().count(), 4);
+                let name = contents.lines().next().unwrap_or_default();
+                Ok(name.trim_end_matches(['1', '2']).to_string())
+            })
+            .collect::<Result<_, std::io::Error>>()?;
+        assert!(names.iter().all(|name| name == &names[0]), "{:?}", names);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn unaligned_bam_input_to_fastq_output() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let out = dir.path().join("out.fq");
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![READS, "tests/cases/unaligned.sam", "-n", "3", "-o"])
+        .arg(&out);
+
+    cmd.assert().success();
+
+    let actual = std::fs::read_to_string(&out)?;
+    assert!(actual.starts_with("@read1\nACGTACGC\n+\nIIIIIIII\n"));
+
+    Ok(())
+}
+
+#[test]
+fn unaligned_bam_input_to_stdout_keeps_format() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![
+        READS,
+        "tests/cases/unaligned.sam",
+        "-n",
+        "1",
+        "-s",
+        "1",
+    ]);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("@RG\tID:run1"))
+        .stdout(predicate::str::contains("ch:i:"));
+
+    Ok(())
+}
+
+#[test]
+fn fastq_input_to_bam_output_raises_error() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![
+        READS,
+        "tests/cases/file1.fq.gz",
+        "-n",
+        "1",
+        "-o",
+        "/tmp/out.bam",
+    ]);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "only possible with SAM/BAM/CRAM input",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn fasta_output_inferred_from_extension() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let out = dir.path().join("out.fa");
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![READS, "tests/cases/r1.fq.gz", "-n", "2", "-o"])
+        .arg(&out)
+        .args(["--line-width", "2", "--strip-comments"]);
+
+    cmd.assert().success();
+
+    let actual = std::fs::read_to_string(&out)?;
+    assert_eq!(actual, ">read1/1\nAC\nGT\n>read2/1\nAC\nGT\n");
+
+    Ok(())
+}
+
+#[test]
+fn shuffle_output_keeps_linked_files_in_the_same_order() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let inputs: Vec<_> = (1..=2)
+        .map(|mate| {
+            let path = dir.path().join(format!("in{}.fq", mate));
+            let text: String = (0..50)
+                .map(|i| format!("@read{}/{}\nACGT\n+\n!!!!\n", i, mate))
+                .collect();
+            std::fs::write(&path, text).unwrap();
+            path
+        })
+        .collect();
+    let outputs: Vec<_> = (1..=2)
+        .map(|mate| dir.path().join(format!("out{}.fq", mate)))
+        .collect();
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![
+        READS,
+        "-n",
+        "40",
+        "-s",
+        "3",
+        "--shuffle-output",
+        "--shuffle-chunk-size",
+        "7",
+    ])
+    .args(&inputs)
+    .arg("-o")
+    .arg(&outputs[0])
+    .arg("-o")
+    .arg(&outputs[1]);
+
+    cmd.assert().success();
+
+    let names: Vec<Vec<String>> = outputs
+        .iter()
+        .map(|p| {
+            std::fs::read_to_string(p)
+                .unwrap()
+                .lines()
+                .step_by(4)
+                .map(|l| l.split('/').next().unwrap().to_string())
+                .collect()
+        })
+        .collect();
+    assert_eq!(names[0].len(), 40);
+    assert_eq!(names[0], names[1]);
+    let mut sorted = names[0].clone();
+    sorted.sort_by_key(|n| n.trim_start_matches("@read").parse::<u32>().unwrap());
+    assert_ne!(names[0], sorted);
+
+    Ok(())
+}
+
+#[test]
+fn shuffle_chunk_size_requires_shuffle_output() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(vec![
+        READS,
+        "tests/cases/file1.fq.gz",
+        "-n",
+        "1",
+        "--shuffle-chunk-size",
+        "7",
+    ]);
+
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn batch_reports_every_sample_and_continues_after_failure() -> Result<(), Box<dyn std::error::Error>>
+{
+    let dir = tempfile::tempdir()?;
+    let out = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+    let sheet = dir.path().join("samples.csv");
+    std::fs::write(
+        &sheet,
+        format!(
+            "sample,r1,r2,out1,out2,num\n\
+             paired,tests/cases/r1.fq.gz,tests/cases/r2.fq.gz,{},{},1\n\
+             missing,tests/cases/nope.fq,,{},,3\n\
+             single,tests/cases/file1.fq.gz,,{},,1\n",
+            out("paired1.fq"),
+            out("paired2.fq"),
+            out("missing.fq"),
+            out("single.fq")
+        ),
+    )?;
+    let report = dir.path().join("report.tsv");
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(["batch", "-j", "2", "-s", "1", "-r"])
+        .arg(&report)
+        .arg(&sheet);
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("1 of 3 samples failed"));
+
+    let report = std::fs::read_to_string(report)?;
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("paired\tok\t\t1\t"));
+    assert!(lines[2].starts_with("missing\tfailed\t"));
+    assert!(lines[3].starts_with("single\tok\t\t1\t"));
+    assert!(dir.path().join("paired2.fq").exists());
+    assert!(dir.path().join("single.fq").exists());
+
+    Ok(())
+}
+
+#[test]
+fn batch_cohort_subsamples_to_minimum_coverage_above_floor(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+    let mut sheet = String::from("sample\tr1\tout1\tgenome_size\n");
+    for (sample, nb_reads) in [("a", 10), ("b", 20), ("c", 2)] {
+        let reads: String = (0..nb_reads)
+            .map(|i| format!("@{}{}\nACGTACGTAC\n+\nIIIIIIIIII\n", sample, i))
+            .collect();
+        std::fs::write(path(&format!("{}.fq", sample)), reads)?;
+        sheet.push_str(&format!(
+            "{}\t{}\t{}\t10\n",
+            sample,
+            path(&format!("{}.fq", sample)),
+            path(&format!("{}.sub.fq", sample))
+        ));
+    }
+    std::fs::write(path("samples.tsv"), sheet)?;
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([
+        "batch",
+        "-s",
+        "1",
+        "--cohort-target",
+        "min",
+        "--cohort-floor",
+        "5",
+    ])
+    .arg(path("samples.tsv"));
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let report = String::from_utf8(output)?;
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[1], "a\tok\t10.00\t10\t100\t10.00\ttrue\t");
+    assert_eq!(lines[2], "b\tok\t20.00\t10\t100\t10.00\ttrue\t");
+    assert!(lines[3].starts_with("c\texcluded\t2.00\t"));
+    assert!(!dir.path().join("c.sub.fq").exists());
+    assert_eq!(
+        std::fs::read_to_string(path("b.sub.fq"))?.lines().count(),
+        40
+    );
+
+    Ok(())
+}
+
+#[test]
+fn batch_cohort_target_conflicts_with_coverage_column() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let sheet = dir.path().join("samples.tsv");
+    std::fs::write(
+        &sheet,
+        "sample\tr1\tout1\tgenome_size\tcoverage\ns1\ttests/cases/file1.fq.gz\tout.fq\t1kb\t3\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(["batch", "--cohort-target", "p50"]).arg(&sheet);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "The coverage column can't be used",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn watch_subsamples_once_enough_chunks_arrive() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let chunks = dir.path().to_path_buf();
+    let output = dir.path().join("sub.fq");
+    let write_chunk = move |i: usize| {
+        let reads: String = (0..10)
+            .map(|j| format!("@read{}_{}\nACGTACGTAC\n+\nIIIIIIIIII\n", i, j))
+            .collect();
+        std::fs::write(chunks.join(format!("chunk_{}.fastq", i)), reads).unwrap();
+    };
+    write_chunk(0);
+    // the remaining chunks arrive while watching
+    let writer = std::thread::spawn(move || {
+        for i in 1..4 {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            write_chunk(i);
+        }
+    });
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args(["watch", "-g", "100", "-c", "2", "-m", "0.2", "-i", "0.02"])
+        .args(["-t", "30", "-s", "1", "-o"])
+        .arg(&output)
+        .arg(dir.path());
+
+    cmd.assert().success();
+    writer.join().unwrap();
+
+    // 300 bases are needed to exceed 2x + 20%, but only 200 are kept
+    let kept = std::fs::read_to_string(&output)?;
+    assert_eq!(kept.lines().count(), 20 * 4);
+
+    Ok(())
+}
+
+#[test]
+fn watch_times_out_if_target_not_reached() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(
+        dir.path().join("chunk_0.fq"),
+        "@read1\nACGT\n+\nIIII\n@read2\nACGT\n+\nIIII\n",
+    )?;
+
+    let out_dir = tempfile::tempdir()?;
+    let output = out_dir.path().join("out.fq");
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([
+        "watch", "-g", "100", "-c", "2", "-i", "0.01", "-t", "0.2", "-o",
+    ])
+    .arg(&output)
+    .arg(dir.path());
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Target not reached"));
+    // no output is created, so it can't be mistaken for a subsample
+    assert!(!output.exists());
+
+    Ok(())
+}
+
+#[test]
+fn top_up_contains_previous_subsample() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("in.fq");
+    let reads: String = (0..50)
+        .map(|i| format!("@read{} comment\nACGTACGTAC\n+\nIIIIIIIIII\n", i))
+        .collect();
+    std::fs::write(&input, reads)?;
+    let ids = |path: &std::path::Path| -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .step_by(4)
+            .map(|l| l.split(' ').next().unwrap().to_string())
+            .collect()
+    };
+
+    let previous = dir.path().join("prev.fq");
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "-b", "100", "-s", "1", "-o"])
+        .arg(&previous)
+        .arg(&input);
+    cmd.assert().success();
+
+    let topped_up = dir.path().join("new.fq");
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "-b", "250", "-s", "2", "--top-up"])
+        .arg(&previous)
+        .arg("-o")
+        .arg(&topped_up)
+        .arg(&input);
+    cmd.assert().success();
+
+    let previous_ids = ids(&previous);
+    let new_ids = ids(&topped_up);
+    assert_eq!(previous_ids.len(), 10);
+    assert_eq!(new_ids.len(), 25);
+    assert!(previous_ids.iter().all(|id| new_ids.contains(id)));
+
+    // the previous selection can also be given as a list of IDs
+    let id_list = dir.path().join("prev.txt");
+    std::fs::write(&id_list, previous_ids.join("\n"))?;
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "-n", "12", "--top-up"])
+        .arg(&id_list)
+        .arg(&input);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let list_ids: Vec<String> = String::from_utf8(output)?
+        .lines()
+        .step_by(4)
+        .map(|l| l.split(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(list_ids.len(), 12);
+    assert!(previous_ids.iter().all(|id| list_ids.contains(id)));
+
+    Ok(())
+}
+
+#[test]
+fn top_up_with_unknown_previous_read_raises_error() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let id_list = dir.path().join("prev.txt");
+    std::fs::write(&id_list, "not_a_read\n")?;
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "-n", "1", "--top-up"])
+        .arg(&id_list)
+        .arg("tests/cases/file1.fq.gz");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "1 of the 1 previously selected reads are not in the first input file",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn unselected_output_splits_paired_input() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("in.fq");
+    let reads: String = (0..20)
+        .map(|i| format!("@read{}\nACGTACGTAC\n+\nIIIIIIIIII\n", i))
+        .collect();
+    std::fs::write(&input, reads)?;
+    let path = |name: &str| dir.path().join(name);
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "-n", "7", "-s", "3"])
+        .arg(&input)
+        .arg(&input)
+        .arg("-o")
+        .arg(path("kept1.fq"))
+        .arg("-o")
+        .arg(path("kept2.fq"))
+        .arg("--unselected-output")
+        .arg(path("rest1.fq.gz"))
+        .arg("--unselected-output")
+        .arg(path("rest2.fa"));
+    cmd.assert().success();
+
+    let ids = |path: &std::path::Path, step: usize| -> Vec<String> {
+        let (reader, _) = niffler::from_path(path).unwrap();
+        std::io::BufRead::lines(std::io::BufReader::new(reader))
+            .step_by(step)
+            .map(|l| l.unwrap()[1..].to_string())
+            .collect()
+    };
+    let kept = ids(&path("kept1.fq"), 4);
+    let rest = ids(&path("rest1.fq.gz"), 4);
+    assert_eq!(kept.len(), 7);
+    assert_eq!(rest.len(), 13);
+    assert!(kept.iter().all(|id| !rest.contains(id)));
+    assert_eq!(kept, ids(&path("kept2.fq"), 4));
+    assert_eq!(rest, ids(&path("rest2.fa"), 2));
+
+    Ok(())
+}
+
+#[test]
+fn unselected_output_for_unaligned_bam() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let kept = dir.path().join("kept.bam");
+    let rest = dir.path().join("rest.fq");
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "tests/cases/unaligned.sam", "-n", "1", "-o"])
+        .arg(&kept)
+        .arg("--unselected-output")
+        .arg(&rest);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--unselected-output must be the same kind of file",
+    ));
+    // neither output is created for an invalid combination
+    assert!(!kept.exists());
+    assert!(!rest.exists());
+
+    let rest = dir.path().join("rest.sam");
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([READS, "tests/cases/unaligned.sam", "-n", "1", "-o"])
+        .arg(&kept)
+        .arg("--unselected-output")
+        .arg(&rest);
+    cmd.assert().success();
+
+    let records = std::fs::read_to_string(&rest)?;
+    assert_eq!(records.lines().filter(|l| !l.starts_with('@')).count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn tag_writes_all_paired_reads_with_keep_comment() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let out1 = dir.path().join("out1.fq");
+    let out2 = dir.path().join("out2.fq");
+
+    let mut cmd = Command::cargo_bin(BIN)?;
+    cmd.args([
+        READS,
+        "tests/cases/r1.fq.gz",
+        "tests/cases/r2.fq.gz",
+        "-n",
+        "1",
+        "--tag",
+        "-o",
+    ])
+    .arg(&out1)
+    .arg("-o")
+    .arg(&out2);
+    cmd.assert().success();
+
+    let keep = |path: &std::path::Path| -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .step_by(4)
+            .map(|l| l.rsplit(' ').next().unwrap().to_string())
+            .collect()
+    };
+    let keep1 = keep(&out1);
+    assert_eq!(keep1.len(), 2);
+    assert_eq!(keep1.iter().filter(|&c| c == "rasusa:keep=1").count(), 1);
+    assert_eq!(keep1, keep(&out2));
+
+    Ok(())
+}
+
+#[test]
+fn hash_selects_same_reads_from_reordered_input() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let records: Vec<String> = (0..50)
+        .map(|i| format!("@read{}/1\nACGTACGTAC\n+\nIIIIIIIIII\n", i))
+        .collect();
+    let forward = dir.path().join("forward.fq");
+    std::fs::write(&forward, records.concat())?;
+    let reversed = dir.path().join("reversed.fq.gz");
+    let mut writer = niffler::to_path(
+        &reversed,
+        niffler::compression::Format::Gzip,
+        niffler::Level::One,
+    )?;
+    for record in records.iter().rev() {
+        std::io::Write::write_all(&mut writer, record.as_bytes())?;
+    }
+    drop(writer);
+
+    let selected = |input: &std::path::Path, args: &[&str]| -> Vec<String> {
+        let output = Command::cargo_bin(BIN)
+            .unwrap()
+            .arg(READS)
+            .arg(input)
+            .args(args)
+            .args(["--hash", "-s", "7"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let mut ids: Vec<String> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .step_by(4)
+            .map(String::from)
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    for args in [["-b", "200"], ["-f", "0.3"]] {
+        let ids = selected(&forward, &args);
+        assert!(!ids.is_empty() && ids.len() < 50);
+        assert_eq!(ids, selected(&reversed, &args));
+    }
+
+    Ok(())
+}